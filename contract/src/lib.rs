@@ -1,37 +1,634 @@
+// `send_message` takes each optional field as its own named JSON argument, and
+// the `Ext` methods `near_bindgen` generates can't carry a per-function allow
+#![allow(clippy::too_many_arguments)]
+
+use std::collections::{HashMap, HashSet};
+
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
+use near_sdk::collections::{LookupMap, LookupSet, UnorderedSet};
+use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::{env, log, near_bindgen, AccountId, BorshStorageKey, NearToken, PanicOnDefault, Promise};
+use near_sdk::{
+    assert_one_yocto, env, ext_contract, log, near_bindgen, AccountId, BorshStorageKey, Gas,
+    NearToken, PanicOnDefault, Promise, PromiseError, PromiseOrValue,
+};
 
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+
+/// NEP-297 `standard` field of emitted events until the owner picks another
+const DEFAULT_EVENT_STANDARD: &str = "whisper";
+
+/// Upper bound on a custom `event_standard` in bytes
+const MAX_EVENT_STANDARD_LEN: usize = 32;
+
+/// Semantic version of the deployed contract build, kept in step with
+/// `Cargo.toml`. Independent of `EVENT_VERSION`.
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// NEP-297 `version` of the event schema; bump when payloads change
+const EVENT_VERSION: &str = "1.0.0";
+
+/// Most contacts one `add_contacts` call may add
+const MAX_CONTACTS_PER_CALL: usize = 100;
+
+/// Default `group_deposit`, the base deposit for creating a group
+const DEFAULT_GROUP_DEPOSIT: NearToken = NearToken::from_millinear(10);
+
+/// What the first deployment kept, at minimum, from every registration.
+/// Migrated profiles are credited with it so `unregister` refunds it.
+const LEGACY_REGISTRATION_DEPOSIT: NearToken = NearToken::from_millinear(10);
+
+/// Storage deposit charged per group member on top of the base group deposit
+const GROUP_MEMBER_DEPOSIT: NearToken = NearToken::from_millinear(1);
+
+/// Storage deposit for `create_group_invite`
+const GROUP_INVITE_DEPOSIT: NearToken = NearToken::from_millinear(1);
+
+/// Storage deposit for `create_channel`
+const CHANNEL_DEPOSIT: NearToken = NearToken::from_millinear(10);
+
+/// Upper bound on messages in one `batch_send_message` call
+const MAX_BATCH_SIZE: usize = 50;
+
+/// Upper bound on device keys per profile
+const MAX_DEVICE_KEYS: usize = 10;
+
+/// Upper bound on base64 ciphertext length, bounding gas and event size
+const MAX_ENCRYPTED_BODY_LEN: usize = 16384;
+
+/// Upper bound on members per group, creator included
+const MAX_GROUP_MEMBERS: u32 = 256;
+
+/// Upper bound on group and channel id length in bytes
+const MAX_GROUP_ID_LEN: usize = 64;
+
+/// Upper bound on accounts in one batch profile lookup
+const MAX_PROFILE_BATCH: usize = 100;
+
+/// Upper bound on accounts in one `profiles_exist` call
+const MAX_EXISTENCE_BATCH: usize = 200;
+
+/// Upper bound on `display_name` length in bytes
+const MAX_DISPLAY_NAME_LEN: usize = 64;
+
+/// Default lower bound on `display_name` length in characters
+const DEFAULT_MIN_DISPLAY_NAME_LEN: u8 = 2;
+
+/// Upper bound on the owner's banned display name substrings
+const MAX_BANNED_SUBSTRINGS: u64 = 100;
+
+/// Bytes NEAR charges for every stored record on top of its key and value
+const STORAGE_RECORD_OVERHEAD: u64 = 40;
+
+/// Longest valid NEAR account id, used for worst-case storage estimates
+const MAX_ACCOUNT_ID_LEN: u64 = 64;
+
+/// Upper bound on `react` reaction length in bytes
+const MAX_REACTION_LEN: usize = 16;
+
+/// Distinct reactions tallied per message; further new ones are rejected
+const MAX_DISTINCT_REACTIONS: usize = 20;
+
+/// Upper bound on message ids in one `mark_read` call
+const MAX_READ_BATCH: usize = 100;
+
+/// Retired keys kept per account by `get_key_history`
+const MAX_KEY_HISTORY: usize = 20;
+
+/// Relay ordering hint for messages that don't ask for one
+const PRIORITY_LOW: u8 = 0;
+
+/// Relay ordering hint stamped on payment messages, leaving headroom above
+/// it for clients with more urgent traffic
+const PRIORITY_HIGH: u8 = 192;
+
+/// Upper bound on the client-reported `thread_depth` of a reply
+const MAX_THREAD_DEPTH: u32 = 64;
+
+/// Upper bound on `send_message` `category` length in bytes
+const MAX_CATEGORY_LEN: usize = 32;
+
+/// Upper bound on `client_version` length in bytes
+const MAX_CLIENT_VERSION_LEN: usize = 16;
+
+/// Upper bound on a group message `attachment_ref` in bytes
+const MAX_ATTACHMENT_REF_LEN: usize = 256;
+
+/// Upper bound on a profile `metadata_uri` in bytes
+const MAX_METADATA_URI_LEN: usize = 256;
+
+/// Upper bound on a profile `linked_profile` in bytes
+const MAX_LINKED_PROFILE_LEN: usize = 128;
+
+/// Upper bound on a profile `auto_reply` in bytes
+const MAX_AUTO_REPLY_LEN: usize = 256;
+
+/// Upper bound on `client_msg_id` length in bytes, enough for a UUID
+const MAX_CLIENT_MSG_ID_LEN: usize = 64;
+
+/// MIME types accepted as `send_message` `content_type`
+const ALLOWED_CONTENT_TYPES: &[&str] = &[
+    "text/plain",
+    "text/markdown",
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "audio/ogg",
+    "audio/mpeg",
+    "video/mp4",
+    "application/pdf",
+    "application/octet-stream",
+];
+
+/// Algorithms accepted as `compression`, applied to the plaintext before
+/// encryption
+const ALLOWED_COMPRESSION: &[&str] = &["none", "gzip", "zstd"];
+
+/// Upper bound on page size for paginated views
+const MAX_PAGE_LIMIT: u64 = 100;
+
+/// Default idle time after which `prune_inactive_profiles` may remove a profile
+const DEFAULT_PRUNE_AFTER_SECONDS: u64 = 365 * 24 * 60 * 60;
+
+/// How long the recipient has to claim an escrow before the sender may reclaim it
+const ESCROW_TIMEOUT_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Smallest escrowable amount, so the held balance covers the escrow record's storage
+const MIN_ESCROW_AMOUNT: NearToken = NearToken::from_millinear(10);
+
+/// Conservative gas for a `send_group_message` call, before per-member costs
+const GROUP_MESSAGE_BASE_GAS: Gas = Gas::from_tgas(5);
+
+/// Extra gas per group member for loading and re-saving the member list
+const GROUP_MESSAGE_GAS_PER_MEMBER: Gas = Gas::from_ggas(100);
+
+/// Gas a payment call must still have left when it schedules the NEAR
+/// transfer. Transfer actions carry no gas of their own, so this is reserved
+/// out of the caller's prepaid gas instead of forwarded with the receipt.
+const TRANSFER_GAS: Gas = Gas::from_tgas(5);
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas::from_tgas(10);
+const GAS_FOR_FT_CALLBACK: Gas = Gas::from_tgas(5);
+
+#[ext_contract(ext_ft)]
+#[allow(dead_code)]
+trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
 
 #[derive(BorshStorageKey, BorshSerialize)]
 #[borsh(crate = "near_sdk::borsh")]
 enum StorageKey {
     Profiles,
     Groups,
+    ProfileIndex,
+    SentCounts,
+    BlockList,
+    DisplayNames,
+    Contacts,
+    Escrows,
+    StorageBalances,
+    KeyHistory,
+    Allowlist,
+    GroupsByCreator,
+    InboxSeqs,
+    InboundWindows,
+    Channels,
+    ChannelSubscribers,
+    LastMessageIds,
+    GroupInvites,
+    ValueReceived,
+    LastActive,
+    GroupReadCursors,
+    BannedSubstrings,
+    GroupMutes,
+    ConversationCounts,
+    ContactLists,
+    PendingMessages,
+    ReactionCounts,
+    GroupRateWindows,
+    FtTokens,
 }
 
 /// A registered messaging profile
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[derive(BorshSerialize, Serialize, Deserialize, Clone)]
 #[borsh(crate = "near_sdk::borsh")]
 #[serde(crate = "near_sdk::serde")]
 pub struct MessagingProfile {
     pub x25519_pubkey: String,
     pub key_version: u32,
+    /// First registration; key rotations leave it alone
     pub registered_at: u64,
     pub display_name: Option<String>,
+    /// Set by `revoke_key`, cleared by the next `register_key`
+    #[serde(default)]
+    pub revoked: bool,
+    /// Storage cost kept by `register_key`, refunded by `unregister`
+    #[serde(default)]
+    pub storage_paid: NearToken,
+    /// Extra per-device keys; `x25519_pubkey` remains the primary key
+    #[serde(default)]
+    pub devices: Vec<DeviceKey>,
+    /// Only accept messages from accounts in this profile's contacts
+    #[serde(default)]
+    pub contacts_only: bool,
+    /// NEAR that non-contacts must attach to `send_message` this profile
+    #[serde(default)]
+    pub inbound_fee: Option<NearToken>,
+    /// Verification badge set by the contract owner; survives rotations
+    #[serde(default)]
+    pub verified: bool,
+    /// Cap on direct messages this profile accepts per window
+    #[serde(default)]
+    pub inbound_rate_limit: Option<RateLimit>,
+    /// Cleared by `set_active(false)` to stop accepting messages while
+    /// keeping the key and storage deposit
+    #[serde(default = "default_true")]
+    pub active: bool,
+    /// Optional signing key for message authenticity, separate from the
+    /// X25519 encryption key
+    #[serde(default)]
+    pub ed25519_pubkey: Option<String>,
+    /// Cleared to refuse payment messages, e.g. unsolicited tips
+    #[serde(default = "default_true")]
+    pub accepts_payments: bool,
+    /// `https://` or `ipfs://` link to avatar and other profile metadata
+    #[serde(default)]
+    pub metadata_uri: Option<String>,
+    /// Out-of-office notice sent back to whoever messages this profile.
+    /// Stored in plain text, since the contract can't encrypt to the sender.
+    #[serde(default)]
+    pub auto_reply: Option<String>,
+    /// Pointer to an external profile, e.g. a NEAR Social DB path
+    #[serde(default)]
+    pub linked_profile: Option<String>,
 }
 
-/// Group chat metadata
+// Hand-written because `active` defaults to true
+impl Default for MessagingProfile {
+    fn default() -> Self {
+        Self {
+            x25519_pubkey: String::new(),
+            key_version: 0,
+            registered_at: 0,
+            display_name: None,
+            revoked: false,
+            storage_paid: NearToken::from_yoctonear(0),
+            devices: Vec::new(),
+            contacts_only: false,
+            inbound_fee: None,
+            verified: false,
+            inbound_rate_limit: None,
+            active: true,
+            ed25519_pubkey: None,
+            accepts_payments: true,
+            metadata_uri: None,
+            auto_reply: None,
+            linked_profile: None,
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// At most `per_window` messages every `window_seconds`, for inboxes and
+/// group members
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct RateLimit {
+    pub per_window: u32,
+    pub window_seconds: u64,
+}
+
+/// An additional X25519 key for one of the account's devices
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeviceKey {
+    pub device_id: String,
+    pub x25519_pubkey: String,
+    pub key_version: u32,
+}
+
+/// A primary key replaced by a later `register_key`
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[borsh(crate = "near_sdk::borsh")]
 #[serde(crate = "near_sdk::serde")]
+pub struct KeyHistoryEntry {
+    pub x25519_pubkey: String,
+    pub key_version: u32,
+    pub retired_at: u64,
+}
+
+// Profiles stored before a field was appended end early, so every field after
+// `display_name` must be read with `read_or_default`.
+impl BorshDeserialize for MessagingProfile {
+    fn deserialize_reader<R: borsh::io::Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        Ok(Self {
+            x25519_pubkey: BorshDeserialize::deserialize_reader(reader)?,
+            key_version: BorshDeserialize::deserialize_reader(reader)?,
+            registered_at: BorshDeserialize::deserialize_reader(reader)?,
+            display_name: BorshDeserialize::deserialize_reader(reader)?,
+            revoked: read_or_default(reader)?,
+            storage_paid: read_or_default(reader)?,
+            devices: read_or_default(reader)?,
+            contacts_only: read_or_default(reader)?,
+            inbound_fee: read_or_default(reader)?,
+            verified: read_or_default(reader)?,
+            inbound_rate_limit: read_or_default(reader)?,
+            active: read_or(reader, true)?,
+            ed25519_pubkey: read_or_default(reader)?,
+            accepts_payments: read_or(reader, true)?,
+            metadata_uri: read_or_default(reader)?,
+            auto_reply: read_or_default(reader)?,
+            linked_profile: read_or_default(reader)?,
+        })
+    }
+}
+
+/// Reads a trailing field, falling back to its default when the stored record
+/// predates it.
+fn read_or_default<T, R>(reader: &mut R) -> borsh::io::Result<T>
+where
+    T: BorshDeserialize + Default,
+    R: borsh::io::Read,
+{
+    read_or(reader, T::default())
+}
+
+/// `read_or_default` for fields whose fallback isn't `T::default()`
+fn read_or<T, R>(reader: &mut R, fallback: T) -> borsh::io::Result<T>
+where
+    T: BorshDeserialize,
+    R: borsh::io::Read,
+{
+    let mut first = [0u8; 1];
+    if reader.read(&mut first)? == 0 {
+        return Ok(fallback);
+    }
+    T::deserialize_reader(&mut borsh::io::Read::chain(first.as_slice(), reader))
+}
+
+/// Group chat metadata
+#[derive(BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
 pub struct GroupChat {
     pub group_id: String,
     pub creator: AccountId,
     pub created_at: u64,
     pub name: Option<String>,
+    /// Accounts allowed to post; always includes the creator
+    #[serde(default)]
+    pub members: Vec<AccountId>,
+    /// Bumped on member removal and by `rotate_group_key` so clients re-key
+    #[serde(default)]
+    pub group_key_version: u32,
+    #[serde(default)]
+    pub message_count: u64,
+    /// Open groups let anyone `join_group`; closed groups only grow via
+    /// `add_group_member`
+    #[serde(default)]
+    pub is_open: bool,
+    /// Always `members.len()`, kept so the size limit is checked in O(1)
+    #[serde(default)]
+    pub member_count: u32,
+    /// Members who may manage membership and keys, besides the creator
+    #[serde(default)]
+    pub admins: Vec<AccountId>,
+    /// Encrypted announcement under the group key, set by admins
+    #[serde(default)]
+    pub pin: Option<GroupPin>,
+    /// Per-member posting cap, set by admins
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// Deposits still held for the group: the base deposit plus one
+    /// `GROUP_MEMBER_DEPOSIT` per member. Zero for groups that predate it.
+    #[serde(default)]
+    pub deposit: NearToken,
+}
+
+/// Pinned group message; readers decrypt it with `group_key_version`
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupPin {
+    pub encrypted_body: String,
+    pub nonce: String,
+    pub group_key_version: u32,
+    pub pinned_by: AccountId,
+    pub updated_at: u64,
+}
+
+// Same trailing-field rule as `MessagingProfile`. Groups created before
+// membership was stored get the creator as their only member.
+impl BorshDeserialize for GroupChat {
+    fn deserialize_reader<R: borsh::io::Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        let mut group = Self {
+            group_id: BorshDeserialize::deserialize_reader(reader)?,
+            creator: BorshDeserialize::deserialize_reader(reader)?,
+            created_at: BorshDeserialize::deserialize_reader(reader)?,
+            name: BorshDeserialize::deserialize_reader(reader)?,
+            members: read_or_default(reader)?,
+            group_key_version: read_or_default(reader)?,
+            message_count: read_or_default(reader)?,
+            is_open: read_or_default(reader)?,
+            member_count: read_or_default(reader)?,
+            admins: read_or_default(reader)?,
+            pin: read_or_default(reader)?,
+            rate_limit: read_or_default(reader)?,
+            deposit: read_or_default(reader)?,
+        };
+        if group.members.is_empty() {
+            group.members.push(group.creator.clone());
+            group.group_key_version = 1;
+        }
+        if group.member_count == 0 {
+            group.member_count = group.members.len() as u32;
+        }
+        Ok(group)
+    }
+}
+
+/// One entry of a `batch_send_message` call
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchMessageItem {
+    pub to: AccountId,
+    pub encrypted_body: String,
+    pub nonce: String,
+    pub recipient_key_version: u32,
+    pub reply_to: Option<String>,
+}
+
+/// Message fields carried in the `msg` of an `ft_transfer_call` to this contract
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtMessage {
+    pub to: AccountId,
+    pub encrypted_body: String,
+    pub nonce: String,
+    pub recipient_key_version: u32,
+    pub reply_to: Option<String>,
+}
+
+/// Single-use invite into a group, created by an admin for invite links
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct GroupInvite {
+    pub group_id: String,
+    pub created_by: AccountId,
+    pub expires_at: u64,
+    /// Set once the invite has been used
+    pub accepted_by: Option<AccountId>,
+}
+
+/// One-to-many broadcast channel. Only the creator posts; subscribers hold no
+/// per-member keys, so the channel key is shared out of band.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct Channel {
+    pub channel_id: String,
+    pub creator: AccountId,
+    pub created_at: u64,
+    pub name: Option<String>,
+    pub subscriber_count: u64,
+}
+
+/// Direct message held until `deliver_scheduled` publishes it
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingMessage {
+    pub from: AccountId,
+    pub to: AccountId,
+    pub encrypted_body: String,
+    pub nonce: String,
+    pub recipient_key_version: u32,
+    pub deliver_at: u64,
+    /// Refunded to the sender once the message is delivered or cancelled
+    pub storage_paid: NearToken,
+}
+
+/// NEAR held by the contract for an escrow payment message
+#[derive(BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct Escrow {
+    pub from: AccountId,
+    pub to: AccountId,
+    pub amount: NearToken,
+    pub created_at: u64,
+    /// The recipient must `acknowledge` the message before claiming
+    #[serde(default)]
+    pub require_ack: bool,
+    #[serde(default)]
+    pub acknowledged: bool,
+}
+
+// Same trailing-field rule as `MessagingProfile`
+impl BorshDeserialize for Escrow {
+    fn deserialize_reader<R: borsh::io::Read>(reader: &mut R) -> borsh::io::Result<Self> {
+        Ok(Self {
+            from: BorshDeserialize::deserialize_reader(reader)?,
+            to: BorshDeserialize::deserialize_reader(reader)?,
+            amount: BorshDeserialize::deserialize_reader(reader)?,
+            created_at: BorshDeserialize::deserialize_reader(reader)?,
+            require_ack: read_or_default(reader)?,
+            acknowledged: read_or_default(reader)?,
+        })
+    }
+}
+
+/// NEP-145 storage balance. `total - available` is the part currently paying
+/// for the account's profile.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// NEP-145 storage balance bounds
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+/// Outcome of `send_message_checked`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde", tag = "status", rename_all = "snake_case")]
+pub enum SendResult {
+    Sent { id: u64 },
+    SelfMessage,
+    RecipientNotRegistered,
+    RecipientRevoked,
+    RecipientInactive,
+    Blocked,
+    ContactsOnly,
+    RateLimited,
+    StaleKeyVersion,
+    FeeRequired { amount: U128 },
+}
+
+/// Why `check_can_send` refused a message
+enum SendRejection {
+    SelfMessage,
+    RecipientNotRegistered,
+    RecipientRevoked,
+    RecipientInactive,
+    Blocked,
+    ContactsOnly,
+    RateLimited,
+}
+
+impl SendRejection {
+    fn message(&self) -> &'static str {
+        match self {
+            Self::SelfMessage => "Cannot message yourself",
+            Self::RecipientNotRegistered => "Recipient has no registered messaging key",
+            Self::RecipientRevoked => "Recipient key is revoked",
+            Self::RecipientInactive => "Recipient profile is inactive",
+            Self::Blocked => "Sender is blocked by recipient",
+            Self::ContactsOnly => "Recipient only accepts messages from contacts",
+            Self::RateLimited => "Recipient inbox rate limit exceeded",
+        }
+    }
+}
+
+impl From<SendRejection> for SendResult {
+    fn from(rejection: SendRejection) -> Self {
+        match rejection {
+            SendRejection::SelfMessage => Self::SelfMessage,
+            SendRejection::RecipientNotRegistered => Self::RecipientNotRegistered,
+            SendRejection::RecipientRevoked => Self::RecipientRevoked,
+            SendRejection::RecipientInactive => Self::RecipientInactive,
+            SendRejection::Blocked => Self::Blocked,
+            SendRejection::ContactsOnly => Self::ContactsOnly,
+            SendRejection::RateLimited => Self::RateLimited,
+        }
+    }
+}
+
+/// Contract state as laid out by the first deployment, read by `migrate`
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+struct LegacyWhisperContract {
+    profiles: LookupMap<AccountId, MessagingProfile>,
+    groups: LookupMap<String, GroupChat>,
+    profile_count: u64,
+    message_count: u64,
+    owner: AccountId,
 }
 
 /// NEP-297 event
@@ -44,26 +641,295 @@ struct WhisperEvent<'a> {
     data: serde_json::Value,
 }
 
-fn emit_event(event: &str, data: serde_json::Value) {
-    let ev = WhisperEvent {
-        standard: "whisper",
-        version: "1.0.0",
-        event,
-        data,
-    };
-    let json = serde_json::to_string(&ev).unwrap();
-    log!("EVENT_JSON:{}", json);
+fn assert_valid_x25519_pubkey(pubkey: &str) -> [u8; 32] {
+    let decoded = BASE64
+        .decode(pubkey)
+        .unwrap_or_else(|_| env::panic_str("Invalid base64 pubkey"));
+    decoded
+        .try_into()
+        .unwrap_or_else(|_| env::panic_str("X25519 pubkey must be 32 bytes"))
+}
+
+fn assert_valid_ed25519_pubkey(pubkey: &str) {
+    let decoded = BASE64
+        .decode(pubkey)
+        .unwrap_or_else(|_| env::panic_str("Invalid base64 pubkey"));
+    assert_eq!(decoded.len(), 32, "Ed25519 pubkey must be 32 bytes");
+}
+
+fn validate_metadata_uri(metadata_uri: &Option<String>) {
+    if let Some(uri) = metadata_uri {
+        assert!(
+            uri.len() <= MAX_METADATA_URI_LEN,
+            "Metadata URI must be at most {} bytes",
+            MAX_METADATA_URI_LEN
+        );
+        assert!(
+            uri.starts_with("https://") || uri.starts_with("ipfs://"),
+            "Metadata URI must start with https:// or ipfs://"
+        );
+    }
+}
+
+fn validate_linked_profile(linked_profile: &Option<String>) {
+    if let Some(path) = linked_profile {
+        assert!(
+            !path.is_empty() && path.len() <= MAX_LINKED_PROFILE_LEN,
+            "Linked profile must be 1-{} bytes",
+            MAX_LINKED_PROFILE_LEN
+        );
+    }
+}
+
+/// Checks an XEdDSA signature by the X25519 key over `account_id || key`.
+/// The key is mapped to its Edwards form with sign bit 0, as XEdDSA signers do.
+fn assert_valid_key_proof(account_id: &AccountId, key: &[u8; 32], proof: &str) {
+    let signature: [u8; 64] = BASE64
+        .decode(proof)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .unwrap_or_else(|| env::panic_str("Key proof must be a base64 64-byte signature"));
+    let edwards = MontgomeryPoint(*key)
+        .to_edwards(0)
+        .unwrap_or_else(|| env::panic_str("Invalid X25519 pubkey"));
+
+    let mut message = account_id.as_bytes().to_vec();
+    message.extend_from_slice(key);
+    assert!(
+        env::ed25519_verify(&signature, &message, edwards.compress().as_bytes()),
+        "Invalid key proof"
+    );
+}
+
+fn validate_display_name(name: &str) {
+    assert!(
+        name.len() <= MAX_DISPLAY_NAME_LEN,
+        "Display name must be at most {} bytes",
+        MAX_DISPLAY_NAME_LEN
+    );
+    assert!(
+        !name.chars().any(char::is_control),
+        "Display name must not contain control characters"
+    );
+}
+
+/// Display names are unique case-insensitively so "Alice" can't pose as "alice".
+fn display_name_key(name: &str) -> String {
+    name.to_lowercase()
+}
+
+/// Group and channel ids end up in storage keys and client URLs, so keep them
+/// short and restricted to `[a-zA-Z0-9_-]`. `kind` names which one in errors.
+fn validate_id(kind: &str, id: &str) {
+    assert!(!id.is_empty(), "{} ID must not be empty", kind);
+    assert!(
+        id.len() <= MAX_GROUP_ID_LEN,
+        "{} ID must be at most {} bytes",
+        kind,
+        MAX_GROUP_ID_LEN
+    );
+    assert!(
+        id.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-'),
+        "{} ID may only contain letters, digits, '_' and '-'",
+        kind
+    );
+}
+
+/// Parses a `member_keys` JSON object mapping member account ids to their
+/// base64 wrapped group key, returning the members in order.
+fn parse_member_keys(member_keys: &str) -> Vec<AccountId> {
+    let keys: serde_json::Map<String, serde_json::Value> = serde_json::from_str(member_keys)
+        .unwrap_or_else(|_| env::panic_str("member_keys must be a JSON object"));
+    keys.iter()
+        .map(|(account, key)| {
+            let member: AccountId = account.parse().unwrap_or_else(|_| {
+                env::panic_str(&format!("Invalid member account id in member_keys: {}", account))
+            });
+            let valid_key = key
+                .as_str()
+                .is_some_and(|key| !key.is_empty() && BASE64.decode(key).is_ok());
+            assert!(valid_key, "member_keys value for {} must be non-empty base64", member);
+            member
+        })
+        .collect()
+}
+
+fn validate_client_version(client_version: &Option<String>) {
+    if let Some(version) = client_version {
+        assert!(
+            version.len() <= MAX_CLIENT_VERSION_LEN,
+            "Client version must be at most {} bytes",
+            MAX_CLIENT_VERSION_LEN
+        );
+    }
+}
+
+/// `body_hash` commits to the relayed ciphertext so recipients can detect
+/// tampering; it must be a base64 SHA-256 digest.
+fn validate_body_hash(body_hash: &Option<String>) {
+    if let Some(hash) = body_hash {
+        let decoded = BASE64
+            .decode(hash)
+            .unwrap_or_else(|_| env::panic_str("body_hash must be valid base64"));
+        assert_eq!(decoded.len(), 32, "body_hash must be a 32-byte SHA-256 digest");
+    }
+}
+
+fn group_message_gas(group: &GroupChat) -> Gas {
+    GROUP_MESSAGE_BASE_GAS
+        .saturating_add(GROUP_MESSAGE_GAS_PER_MEMBER.saturating_mul(u64::from(group.member_count)))
+}
+
+/// A `per_window` of 0 means no limit
+fn rate_limit(per_window: u32, window_seconds: u64) -> Option<RateLimit> {
+    if per_window == 0 {
+        return None;
+    }
+    assert!(window_seconds > 0, "Rate limit window must be at least a second");
+    Some(RateLimit {
+        per_window,
+        window_seconds,
+    })
+}
+
+/// The `(window start, count)` after one more message, or `None` if the
+/// current window is already full
+fn next_rate_window(limit: RateLimit, window: Option<(u64, u32)>) -> Option<(u64, u32)> {
+    let now = env::block_timestamp();
+    let window_ns = limit.window_seconds.saturating_mul(1_000_000_000);
+    let (mut window_start, mut count) = window.unwrap_or((now, 0));
+    if now >= window_start.saturating_add(window_ns) {
+        window_start = now;
+        count = 0;
+    }
+    (count < limit.per_window).then_some((window_start, count + 1))
+}
+
+fn validate_compression(compression: &Option<String>) {
+    if let Some(compression) = compression {
+        assert!(
+            ALLOWED_COMPRESSION.contains(&compression.as_str()),
+            "Unsupported compression: {}",
+            compression
+        );
+    }
+}
+
+/// Nonces must decode to the 24 bytes XChaCha20 expects, otherwise the
+/// message can never be decrypted.
+fn validate_ciphertext(encrypted_body: &str, nonce: &str) {
+    assert!(
+        encrypted_body.len() <= MAX_ENCRYPTED_BODY_LEN,
+        "Encrypted body too large"
+    );
+    let decoded = BASE64
+        .decode(nonce)
+        .unwrap_or_else(|_| env::panic_str("Invalid base64 nonce"));
+    assert_eq!(decoded.len(), 24, "Nonce must be 24 bytes");
 }
 
+// Contract state is Borsh-encoded field by field in declaration order, with no
+// field names or versioning. Adding, removing or reordering fields changes the
+// layout, so an upgraded contract must be initialised via `migrate`, which
+// decodes the deployed layout and fills in the rest from `WhisperContract::empty`.
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 #[borsh(crate = "near_sdk::borsh")]
 pub struct WhisperContract {
     profiles: LookupMap<AccountId, MessagingProfile>,
     groups: LookupMap<String, GroupChat>,
+    profile_index: UnorderedSet<AccountId>,
+    sent_counts: LookupMap<AccountId, u64>,
+    /// (recipient, blocked sender) pairs
+    blocked: LookupSet<(AccountId, AccountId)>,
+    /// (owner, contact) pairs
+    contacts: LookupSet<(AccountId, AccountId)>,
+    /// Escrowed payments keyed by message id
+    escrows: LookupMap<u64, Escrow>,
+    /// NEP-145 storage deposits, drawn on by `register_key`
+    storage_balances: LookupMap<AccountId, StorageBalance>,
+    /// Retired primary keys, oldest first, capped at `MAX_KEY_HISTORY`
+    key_history: LookupMap<AccountId, Vec<KeyHistoryEntry>>,
+    /// Accounts allowed to `register_key` while `allowlist_enabled` is set
+    allowlist: LookupSet<AccountId>,
+    /// Creator -> ids of the groups they created, in creation order
+    groups_by_creator: LookupMap<AccountId, Vec<String>>,
+    /// Lowercased display name -> account that claimed it
+    display_names: LookupMap<String, AccountId>,
+    /// Recipient -> number of direct messages sent to them, so clients can
+    /// spot events a relay dropped
+    inbox_seqs: LookupMap<AccountId, u64>,
+    /// Recipient -> id of the latest direct message sent to them
+    last_message_ids: LookupMap<AccountId, u64>,
+    group_invites: LookupMap<String, GroupInvite>,
+    /// Recipient -> NEAR received through payment messages
+    value_received: LookupMap<AccountId, NearToken>,
+    /// Sender -> timestamp of their latest message, for pruning idle profiles
+    last_active: LookupMap<AccountId, u64>,
+    /// (group id, member) -> id of the last group message the member read
+    group_read_cursors: LookupMap<(String, AccountId), u64>,
+    /// Lowercased substrings no new display name may contain. Iterable,
+    /// unlike a `LookupSet`, so names can be checked against every entry.
+    banned_substrings: UnorderedSet<String>,
+    /// (group id, member) -> muted, for members who ever set it
+    group_mutes: LookupMap<(String, AccountId), bool>,
+    /// (sender, recipient) -> direct messages sent in that direction
+    conversation_counts: LookupMap<(AccountId, AccountId), u64>,
+    /// Owner -> their contacts, so `clear_contacts` can find them
+    contact_lists: LookupMap<AccountId, Vec<AccountId>>,
+    /// Message id -> reaction -> times it was sent
+    reaction_counts: LookupMap<u64, HashMap<String, u32>>,
+    /// Recipient -> (window start, messages received in that window)
+    inbound_windows: LookupMap<AccountId, (u64, u32)>,
+    /// (group id, member) -> (window start, messages posted in that window)
+    group_rate_windows: LookupMap<(String, AccountId), (u64, u32)>,
+    /// NEP-141 contracts trusted to report `sender_id` to `ft_on_transfer`
+    ft_tokens: LookupSet<AccountId>,
+    channels: LookupMap<String, Channel>,
+    /// (channel id, subscriber) pairs
+    channel_subscribers: LookupSet<(String, AccountId)>,
+    /// Scheduled messages keyed by schedule id, not message id
+    pending_messages: LookupMap<u64, PendingMessage>,
+    /// Last schedule id handed out by `schedule_message`
+    scheduled_count: u64,
     profile_count: u64,
     message_count: u64,
+    /// Breakdown of `message_count`, which also counts broadcasts
+    dm_count: u64,
+    payment_message_count: u64,
+    group_message_count: u64,
+    /// Mixed into invite ids so invites created in one block differ
+    invite_count: u64,
     owner: AccountId,
+    paused: bool,
+    require_key_proof: bool,
+    strict_reply_validation: bool,
+    /// Reject direct messages encrypted to a recipient key older than current
+    strict_key_version: bool,
+    /// Permit note-to-self messages, which are otherwise almost always a client bug
+    allow_self_message: bool,
+    allowlist_enabled: bool,
+    /// Advisory retention for relays and indexers, stamped on `message` events
+    default_message_ttl_seconds: u64,
+    /// Floor on what a first `register_key` keeps, on top of measured storage
+    registration_deposit: NearToken,
+    /// Base deposit for `create_group`, before per-member deposits
+    group_deposit: NearToken,
+    /// NEAR owed to users: open escrows plus unused storage deposits.
+    /// `withdraw` never touches it.
+    held_balance: NearToken,
+    /// NEAR delivered by payment messages, counting escrows once claimed
+    total_value_transferred: NearToken,
+    /// NEP-297 `standard` of emitted events, so forks don't collide with
+    /// the canonical namespace
+    event_standard: String,
+    /// Idle time after which `prune_inactive_profiles` may remove a profile
+    prune_after_seconds: u64,
+    /// Shortest display name, in characters, that new names may use
+    min_display_name_len: u8,
+    /// Owner override of `TRANSFER_GAS`
+    transfer_gas: Option<Gas>,
 }
 
 #[near_bindgen]
@@ -71,12 +937,44 @@ impl WhisperContract {
     #[init]
     pub fn new() -> Self {
         assert!(!env::state_exists(), "Already initialized");
-        Self {
-            profiles: LookupMap::new(StorageKey::Profiles),
-            groups: LookupMap::new(StorageKey::Groups),
-            profile_count: 0,
-            message_count: 0,
-            owner: env::predecessor_account_id(),
+        Self::empty(env::predecessor_account_id())
+    }
+
+    /// Upgrades state written by the original (v0.1.0) layout. Profiles,
+    /// groups and counters carry over; everything added since starts empty.
+    /// The old profile map can't be enumerated on-chain, so the deployer
+    /// passes the registered accounts (from `key_registered` events) to
+    /// rebuild `profile_index` and the display name claims. Lists too long
+    /// for one call can be finished with `backfill_profiles`.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(legacy_accounts: Vec<AccountId>) -> Self {
+        let old: LegacyWhisperContract =
+            env::state_read().unwrap_or_else(|| env::panic_str("No state to migrate"));
+        let mut contract = Self {
+            profiles: old.profiles,
+            groups: old.groups,
+            profile_count: old.profile_count,
+            message_count: old.message_count,
+            ..Self::empty(old.owner)
+        };
+        for account_id in &legacy_accounts {
+            contract.backfill_profile(account_id);
+        }
+        contract
+    }
+
+    /// Indexes profiles carried over by `migrate` that it wasn't given.
+    /// Accounts without a profile, or already indexed, are skipped.
+    pub fn backfill_profiles(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        assert!(
+            account_ids.len() <= MAX_BATCH_SIZE,
+            "Batch exceeds {} entries",
+            MAX_BATCH_SIZE
+        );
+        for account_id in &account_ids {
+            self.backfill_profile(account_id);
         }
     }
 
@@ -84,82 +982,715 @@ impl WhisperContract {
     // Key Registration
     // ========================================================================
 
+    /// Storage is paid from the attached deposit first, then from the
+    /// account's `storage_deposit` balance.
     #[payable]
-    pub fn register_key(&mut self, x25519_pubkey: String, display_name: Option<String>) {
+    pub fn register_key(
+        &mut self,
+        x25519_pubkey: String,
+        display_name: Option<String>,
+        proof: Option<String>,
+        ed25519_pubkey: Option<String>,
+        metadata_uri: Option<String>,
+        linked_profile: Option<String>,
+    ) {
         let account_id = env::predecessor_account_id();
+        assert!(
+            !self.allowlist_enabled || self.allowlist.contains(&account_id),
+            "Account is not on the registration allowlist"
+        );
 
-        let decoded = BASE64
-            .decode(&x25519_pubkey)
-            .unwrap_or_else(|_| env::panic_str("Invalid base64 pubkey"));
-        assert_eq!(decoded.len(), 32, "X25519 pubkey must be 32 bytes");
+        let key = assert_valid_x25519_pubkey(&x25519_pubkey);
+        match proof {
+            Some(proof) => assert_valid_key_proof(&account_id, &key, &proof),
+            None => assert!(!self.require_key_proof, "Key proof required"),
+        }
+        if let Some(signing_key) = &ed25519_pubkey {
+            assert_valid_ed25519_pubkey(signing_key);
+        }
+        validate_metadata_uri(&metadata_uri);
+        validate_linked_profile(&linked_profile);
 
-        let existing = self.profiles.get(&account_id);
+        let mut existing = self.profiles.get(&account_id);
         let key_version = existing.as_ref().map_or(1, |p| p.key_version + 1);
+        let storage_before = env::storage_usage();
 
         if existing.is_none() {
-            let deposit = env::attached_deposit();
-            assert!(
-                deposit >= NearToken::from_millinear(10),
-                "Attach at least 0.01 NEAR for storage deposit"
-            );
             self.profile_count += 1;
+            self.allocate_counters(&account_id);
+        }
+        // Also indexes profiles carried over by `migrate` on their next
+        // rotation, crediting the deposit they paid before it
+        if self.profile_index.insert(&account_id) {
+            if let Some(previous) = existing.as_mut() {
+                previous.storage_paid =
+                    previous.storage_paid.saturating_add(LEGACY_REGISTRATION_DEPOSIT);
+            }
+        }
+        let previous_name = existing.as_ref().and_then(|p| p.display_name.as_deref());
+        self.update_display_name_claim(&account_id, previous_name, display_name.as_deref());
+        if let Some(previous) = &existing {
+            self.record_retired_key(&account_id, previous);
+            // Still counts as activity for `prune_inactive_profiles`
+            self.last_active.insert(&account_id, &env::block_timestamp());
         }
 
-        let profile = MessagingProfile {
-            x25519_pubkey: x25519_pubkey.clone(),
-            key_version,
-            registered_at: env::block_timestamp(),
-            display_name: display_name.clone(),
-        };
+        // Rotation replaces the primary key but keeps everything else
+        let mut profile = existing.clone().unwrap_or_default();
+        profile.x25519_pubkey = x25519_pubkey.clone();
+        profile.key_version = key_version;
+        if existing.is_none() {
+            profile.registered_at = env::block_timestamp();
+        }
+        profile.display_name = display_name.clone();
+        if ed25519_pubkey.is_some() {
+            profile.ed25519_pubkey = ed25519_pubkey;
+        }
+        if metadata_uri.is_some() {
+            profile.metadata_uri = metadata_uri;
+        }
+        if linked_profile.is_some() {
+            profile.linked_profile = linked_profile;
+        }
+        profile.revoked = false;
+        self.profiles.insert(&account_id, &profile);
 
+        // `storage_paid` is fixed-size, so re-inserting doesn't change usage
+        let minimum = match existing {
+            None => self.registration_deposit,
+            Some(_) => NearToken::from_yoctonear(0),
+        };
+        let storage_cost = self.charge_storage(&account_id, storage_before, minimum);
+        profile.storage_paid = profile.storage_paid.saturating_add(storage_cost);
         self.profiles.insert(&account_id, &profile);
 
-        emit_event(
-            "key_registered",
-            serde_json::json!({
-                "account_id": account_id.to_string(),
-                "x25519_pubkey": x25519_pubkey,
-                "key_version": key_version,
-                "display_name": display_name,
-            }),
-        );
+        let mut data = serde_json::json!({
+            "account_id": account_id.to_string(),
+            "x25519_pubkey": x25519_pubkey,
+            "key_version": key_version,
+            "display_name": display_name,
+            "ed25519_pubkey": profile.ed25519_pubkey,
+            "metadata_uri": profile.metadata_uri,
+            "linked_profile": profile.linked_profile,
+        });
+        match existing {
+            None => {
+                data["storage_deposit"] = storage_cost.as_yoctonear().to_string().into();
+                self.emit_event("key_registered", data);
+            }
+            Some(previous) => {
+                data["previous_key_version"] = previous.key_version.into();
+                self.emit_event("key_rotated", data);
+            }
+        }
     }
 
-    // ========================================================================
-    // Messaging (event-based, no storage)
-    // ========================================================================
+    /// Owner-only onboarding: registers fresh profiles for many accounts in
+    /// one call, paid for from the attached deposit. Every entry is checked
+    /// before anything is written, so an invalid one aborts the whole batch.
+    #[payable]
+    pub fn batch_register(&mut self, entries: Vec<(AccountId, String, Option<String>)>) {
+        self.assert_owner();
+        let owner = env::predecessor_account_id();
 
-    pub fn send_message(
-        &mut self,
-        to: AccountId,
-        encrypted_body: String,
+        assert!(!entries.is_empty(), "Batch is empty");
+        assert!(
+            entries.len() <= MAX_BATCH_SIZE,
+            "Batch exceeds {} entries",
+            MAX_BATCH_SIZE
+        );
+        let mut seen = HashSet::with_capacity(entries.len());
+        for (account_id, x25519_pubkey, _) in &entries {
+            assert_valid_x25519_pubkey(x25519_pubkey);
+            assert!(
+                !self.profiles.contains_key(account_id),
+                "Account already registered"
+            );
+            assert!(seen.insert(account_id), "Account {} appears twice in batch", account_id);
+        }
+
+        let batch_storage_before = env::storage_usage();
+        let mut registered = Vec::with_capacity(entries.len());
+        for (account_id, x25519_pubkey, display_name) in entries {
+            let storage_before = env::storage_usage();
+            self.profile_count += 1;
+            self.profile_index.insert(&account_id);
+            self.allocate_counters(&account_id);
+            self.update_display_name_claim(&account_id, None, display_name.as_deref());
+
+            let mut profile = MessagingProfile {
+                x25519_pubkey,
+                key_version: 1,
+                registered_at: env::block_timestamp(),
+                display_name,
+                ..Default::default()
+            };
+            self.profiles.insert(&account_id, &profile);
+            let storage_used = env::storage_usage().saturating_sub(storage_before);
+            profile.storage_paid =
+                env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+            self.profiles.insert(&account_id, &profile);
+            registered.push((account_id, profile));
+        }
+        self.charge_storage(&owner, batch_storage_before, NearToken::from_yoctonear(0));
+
+        for (account_id, profile) in registered {
+            self.emit_event(
+                "key_registered",
+                serde_json::json!({
+                    "account_id": account_id.to_string(),
+                    "x25519_pubkey": profile.x25519_pubkey,
+                    "key_version": profile.key_version,
+                    "display_name": profile.display_name,
+                    "storage_deposit": profile.storage_paid.as_yoctonear().to_string(),
+                }),
+            );
+        }
+    }
+
+    /// Changes only the display name; the key and `key_version` stay as
+    /// they are, so contacts don't have to re-encrypt.
+    #[payable]
+    pub fn update_display_name(&mut self, display_name: Option<String>) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        let storage_before = env::storage_usage();
+
+        let previous_name = profile.display_name.take();
+        self.update_display_name_claim(
+            &account_id,
+            previous_name.as_deref(),
+            display_name.as_deref(),
+        );
+        profile.display_name = display_name.clone();
+        self.profiles.insert(&account_id, &profile);
+
+        let storage_cost =
+            self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+        profile.storage_paid = profile.storage_paid.saturating_add(storage_cost);
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "display_name_updated",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "previous_display_name": previous_name,
+                "display_name": display_name,
+            }),
+        );
+    }
+
+    /// Like `update_display_name`, for the profile's metadata URI
+    #[payable]
+    pub fn set_metadata_uri(&mut self, metadata_uri: Option<String>) {
+        let account_id = env::predecessor_account_id();
+        validate_metadata_uri(&metadata_uri);
+        self.update_own_profile(|profile| profile.metadata_uri = metadata_uri.clone());
+
+        self.emit_event(
+            "metadata_uri_updated",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "metadata_uri": metadata_uri,
+            }),
+        );
+    }
+
+    /// Sets or clears the notice `send_message` echoes back to senders
+    #[payable]
+    pub fn set_auto_reply(&mut self, message: Option<String>) {
+        let account_id = env::predecessor_account_id();
+        if let Some(message) = &message {
+            assert!(
+                message.len() <= MAX_AUTO_REPLY_LEN,
+                "Auto-reply must be at most {} bytes",
+                MAX_AUTO_REPLY_LEN
+            );
+        }
+
+        self.update_own_profile(|profile| profile.auto_reply = message.clone());
+
+        self.emit_event(
+            "auto_reply_updated",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "message": message,
+            }),
+        );
+    }
+
+    /// Points the profile at e.g. the caller's NEAR Social profile path;
+    /// clients resolve it, the contract doesn't
+    #[payable]
+    pub fn set_linked_profile(&mut self, linked_profile: Option<String>) {
+        let account_id = env::predecessor_account_id();
+        validate_linked_profile(&linked_profile);
+        self.update_own_profile(|profile| profile.linked_profile = linked_profile.clone());
+
+        self.emit_event(
+            "linked_profile_updated",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "linked_profile": linked_profile,
+            }),
+        );
+    }
+
+    pub fn revoke_key(&mut self) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        assert!(!profile.revoked, "Key is already revoked");
+
+        profile.revoked = true;
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "key_revoked",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "key_version": profile.key_version,
+            }),
+        );
+    }
+
+    /// Soft deactivation: the profile stays registered, but nobody can
+    /// message it until it is reactivated.
+    pub fn set_active(&mut self, active: bool) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        profile.active = active;
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "profile_active_changed",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "active": active,
+            }),
+        );
+    }
+
+    /// Adds a key for another device, or rotates it if `device_id` is known.
+    /// Charges the storage this adds and refunds the rest of the deposit.
+    #[payable]
+    pub fn add_device_key(&mut self, device_id: String, x25519_pubkey: String) {
+        let account_id = env::predecessor_account_id();
+
+        assert_valid_x25519_pubkey(&x25519_pubkey);
+        assert!(!device_id.is_empty(), "Device id must not be empty");
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        let storage_before = env::storage_usage();
+
+        let key_version = match profile.devices.iter_mut().find(|d| d.device_id == device_id) {
+            Some(device) => {
+                device.key_version += 1;
+                device.x25519_pubkey = x25519_pubkey.clone();
+                device.key_version
+            }
+            None => {
+                assert!(
+                    profile.devices.len() < MAX_DEVICE_KEYS,
+                    "At most {} device keys per profile",
+                    MAX_DEVICE_KEYS
+                );
+                profile.devices.push(DeviceKey {
+                    device_id: device_id.clone(),
+                    x25519_pubkey: x25519_pubkey.clone(),
+                    key_version: 1,
+                });
+                1
+            }
+        };
+        self.profiles.insert(&account_id, &profile);
+
+        let storage_cost =
+            self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+        profile.storage_paid = profile.storage_paid.saturating_add(storage_cost);
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "device_key_added",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "device_id": device_id,
+                "x25519_pubkey": x25519_pubkey,
+                "key_version": key_version,
+            }),
+        );
+    }
+
+    pub fn remove_device_key(&mut self, device_id: String) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        let position = profile
+            .devices
+            .iter()
+            .position(|d| d.device_id == device_id)
+            .unwrap_or_else(|| env::panic_str("Unknown device id"));
+        profile.devices.remove(position);
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "device_key_removed",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "device_id": device_id,
+            }),
+        );
+    }
+
+    pub fn unregister(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let (profile, refund) = self.remove_profile(&account_id);
+
+        self.emit_event(
+            "key_unregistered",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "key_version": profile.key_version,
+                "refund": refund.as_yoctonear().to_string(),
+            }),
+        );
+
+        Promise::new(account_id).transfer(refund)
+    }
+
+    // ========================================================================
+    // Storage Management (NEP-145)
+    // ========================================================================
+
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let mut amount = env::attached_deposit();
+        let mut balance = self.storage_balances.get(&account_id).unwrap_or_default();
+
+        // The minimum balance is zero, so a registration-only deposit is
+        // refunded in full
+        if registration_only.unwrap_or(false) {
+            Promise::new(env::predecessor_account_id()).transfer(amount);
+            amount = NearToken::from_yoctonear(0);
+        }
+
+        balance.total = U128(balance.total.0 + amount.as_yoctonear());
+        balance.available = U128(balance.available.0 + amount.as_yoctonear());
+        self.storage_balances.insert(&account_id, &balance);
+        self.held_balance = self.held_balance.saturating_add(amount);
+
+        self.emit_event(
+            "storage_deposited",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "amount": amount.as_yoctonear().to_string(),
+                "total": balance.total,
+            }),
+        );
+
+        balance
+    }
+
+    /// Withdraws `amount` (default: everything available) of the caller's
+    /// unused storage balance
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut balance = self
+            .storage_balances
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No storage balance"));
+
+        let amount = amount.map_or(balance.available.0, |amount| amount.0);
+        assert!(
+            amount <= balance.available.0,
+            "Amount exceeds available storage balance"
+        );
+        balance.total = U128(balance.total.0 - amount);
+        balance.available = U128(balance.available.0 - amount);
+        self.storage_balances.insert(&account_id, &balance);
+        self.held_balance = self
+            .held_balance
+            .saturating_sub(NearToken::from_yoctonear(amount));
+
+        self.emit_event(
+            "storage_withdrawn",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "amount": amount.to_string(),
+                "total": balance.total,
+            }),
+        );
+
+        if amount > 0 {
+            Promise::new(account_id).transfer(NearToken::from_yoctonear(amount));
+        }
+        balance
+    }
+
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_balances.get(&account_id)
+    }
+
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(0),
+            max: None,
+        }
+    }
+
+    // ========================================================================
+    // Messaging (bodies live in events; counters and escrows in storage)
+    // ========================================================================
+
+    /// The attached deposit pays the recipient's inbound fee, then the
+    /// storage of a new conversation count; the rest is refunded.
+    #[payable]
+    pub fn send_message(
+        &mut self,
+        to: AccountId,
+        encrypted_body: String,
         nonce: String,
         recipient_key_version: u32,
         reply_to: Option<String>,
-    ) {
+        expires_at: Option<u64>,
+        content_type: Option<String>,
+        category: Option<String>,
+        client_version: Option<String>,
+        thread_depth: Option<u32>,
+        body_hash: Option<String>,
+        priority: Option<u8>,
+        client_msg_id: Option<String>,
+        compression: Option<String>,
+    ) -> u64 {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+
+        validate_ciphertext(&encrypted_body, &nonce);
+        self.validate_reply_to(&reply_to);
+        if let Some(expires_at) = expires_at {
+            assert!(
+                expires_at > env::block_timestamp(),
+                "expires_at must be in the future"
+            );
+        }
+        if let Some(content_type) = &content_type {
+            assert!(
+                ALLOWED_CONTENT_TYPES.contains(&content_type.as_str()),
+                "Unsupported content type: {}",
+                content_type
+            );
+        }
+        if let Some(category) = &category {
+            assert!(
+                category.len() <= MAX_CATEGORY_LEN,
+                "Category must be at most {} bytes",
+                MAX_CATEGORY_LEN
+            );
+        }
+        validate_client_version(&client_version);
+        validate_body_hash(&body_hash);
+        validate_compression(&compression);
+        // Lets clients dedupe redelivered events on their own identifier
+        if let Some(client_msg_id) = &client_msg_id {
+            assert!(
+                client_msg_id.len() <= MAX_CLIENT_MSG_ID_LEN,
+                "client_msg_id must be at most {} bytes",
+                MAX_CLIENT_MSG_ID_LEN
+            );
+        }
+        // Self-reported, since messages aren't stored to walk the chain
+        if let Some(depth) = thread_depth {
+            assert!(
+                depth <= MAX_THREAD_DEPTH,
+                "Thread depth must be at most {}",
+                MAX_THREAD_DEPTH
+            );
+        }
+        self.assert_can_send(&from, &to);
+        self.validate_recipient_key_version(&to, recipient_key_version);
+        let inbound_fee = self.collect_inbound_fee(&from, &to);
+        let auto_reply = self.profiles.get(&to).and_then(|profile| profile.auto_reply);
+
+        let message_id = self.emit_message(
+            &from,
+            &to,
+            serde_json::json!({
+                "encrypted_body": encrypted_body,
+                "nonce": nonce,
+                "recipient_key_version": recipient_key_version,
+                "reply_to": reply_to,
+                "expires_at": expires_at,
+                "content_type": content_type,
+                "category": category,
+                "client_version": client_version,
+                "thread_depth": thread_depth,
+                "body_hash": body_hash,
+                "priority": priority,
+                "client_msg_id": client_msg_id,
+                "compression": compression,
+                "inbound_fee": inbound_fee.map(|fee| fee.as_yoctonear().to_string()),
+            }),
+        );
+        let deposit = env::attached_deposit()
+            .saturating_sub(inbound_fee.unwrap_or(NearToken::from_yoctonear(0)));
+        let excess = self.fund_conversation(&from, &to, deposit);
+        if !excess.is_zero() {
+            Promise::new(from.clone()).transfer(excess);
+        }
+
+        // Advisory; clients show it to the sender as if the recipient replied
+        if let Some(auto_reply) = auto_reply {
+            self.emit_event(
+                "auto_reply",
+                serde_json::json!({
+                    "from": to.to_string(),
+                    "to": from.to_string(),
+                    "message": auto_reply,
+                    "in_reply_to": message_id,
+                    "timestamp": env::block_timestamp(),
+                }),
+            );
+        }
+
+        message_id
+    }
+
+    /// Onboarding shortcut: `register_key` followed by a first message, all
+    /// or nothing. The attached deposit only pays for registration, so the
+    /// recipient must not charge an inbound fee.
+    #[payable]
+    pub fn register_and_send(
+        &mut self,
+        x25519_pubkey: String,
+        display_name: Option<String>,
+        to: AccountId,
+        encrypted_body: String,
+        nonce: String,
+        recipient_key_version: u32,
+    ) -> u64 {
+        self.assert_not_paused();
+        self.register_key(x25519_pubkey, display_name, None, None, None, None);
         let from = env::predecessor_account_id();
 
+        validate_ciphertext(&encrypted_body, &nonce);
+        self.assert_can_send(&from, &to);
+        self.validate_recipient_key_version(&to, recipient_key_version);
         assert!(
-            self.profiles.get(&to).is_some(),
-            "Recipient has no registered messaging key"
+            self.inbound_fee_for(&from, &to).is_none(),
+            "Recipient charges an inbound fee; use send_message"
         );
 
-        self.message_count += 1;
-        let message_id = self.message_count;
+        self.emit_message(
+            &from,
+            &to,
+            serde_json::json!({
+                "encrypted_body": encrypted_body,
+                "nonce": nonce,
+                "recipient_key_version": recipient_key_version,
+            }),
+        )
+    }
+
+    /// Like `send_message`, but reports gating failures as a `SendResult`
+    /// instead of panicking, so a surrounding batch of calls keeps going.
+    /// Malformed input still panics. Recipients with an inbound fee have to
+    /// be messaged through `send_message`.
+    pub fn send_message_checked(
+        &mut self,
+        to: AccountId,
+        encrypted_body: String,
+        nonce: String,
+        recipient_key_version: u32,
+        reply_to: Option<String>,
+    ) -> SendResult {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+
+        validate_ciphertext(&encrypted_body, &nonce);
+        self.validate_reply_to(&reply_to);
+        if let Err(rejection) = self.check_can_send(&from, &to) {
+            return rejection.into();
+        }
+        if self.is_stale_key_version(&to, recipient_key_version) {
+            return SendResult::StaleKeyVersion;
+        }
+        if let Some(fee) = self.inbound_fee_for(&from, &to) {
+            return SendResult::FeeRequired {
+                amount: U128(fee.as_yoctonear()),
+            };
+        }
 
-        emit_event(
-            "message",
+        let id = self.emit_message(
+            &from,
+            &to,
             serde_json::json!({
-                "id": message_id,
-                "from": from.to_string(),
-                "to": to.to_string(),
                 "encrypted_body": encrypted_body,
                 "nonce": nonce,
                 "recipient_key_version": recipient_key_version,
                 "reply_to": reply_to,
-                "timestamp": env::block_timestamp(),
             }),
         );
+        SendResult::Sent { id }
+    }
+
+    pub fn batch_send_message(&mut self, messages: Vec<BatchMessageItem>) {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+
+        assert!(!messages.is_empty(), "Batch is empty");
+        assert!(
+            messages.len() <= MAX_BATCH_SIZE,
+            "Batch exceeds {} messages",
+            MAX_BATCH_SIZE
+        );
+        // Validate everything before emitting so a bad item leaves no partial batch
+        for item in &messages {
+            validate_ciphertext(&item.encrypted_body, &item.nonce);
+            self.validate_reply_to(&item.reply_to);
+            self.assert_can_send(&from, &item.to);
+            self.validate_recipient_key_version(&item.to, item.recipient_key_version);
+            assert!(
+                self.inbound_fee_for(&from, &item.to).is_none(),
+                "Recipient charges an inbound fee; use send_message"
+            );
+        }
+
+        for item in messages {
+            self.emit_message(
+                &from,
+                &item.to,
+                serde_json::json!({
+                    "encrypted_body": item.encrypted_body,
+                    "nonce": item.nonce,
+                    "recipient_key_version": item.recipient_key_version,
+                    "reply_to": item.reply_to,
+                }),
+            );
+        }
     }
 
     #[payable]
@@ -171,6 +1702,7 @@ impl WhisperContract {
         recipient_key_version: u32,
         reply_to: Option<String>,
     ) -> Promise {
+        self.assert_not_paused();
         let from = env::predecessor_account_id();
         let amount = env::attached_deposit();
 
@@ -178,25 +1710,21 @@ impl WhisperContract {
             amount > NearToken::from_yoctonear(0),
             "Must attach NEAR tokens for payment message"
         );
-        assert!(
-            self.profiles.get(&to).is_some(),
-            "Recipient has no registered messaging key"
-        );
-
-        self.message_count += 1;
-        let message_id = self.message_count;
+        validate_ciphertext(&encrypted_body, &nonce);
+        self.validate_reply_to(&reply_to);
+        self.assert_can_send(&from, &to);
+        self.assert_accepts_payments(&to);
+        self.assert_payment_covers_fee(&from, &to, amount);
+        self.validate_recipient_key_version(&to, recipient_key_version);
 
-        emit_event(
-            "message",
+        self.emit_message(
+            &from,
+            &to,
             serde_json::json!({
-                "id": message_id,
-                "from": from.to_string(),
-                "to": to.to_string(),
                 "encrypted_body": encrypted_body,
                 "nonce": nonce,
                 "recipient_key_version": recipient_key_version,
                 "reply_to": reply_to,
-                "timestamp": env::block_timestamp(),
                 "payment": {
                     "token": "NEAR",
                     "amount": amount.as_yoctonear().to_string(),
@@ -204,218 +1732,8075 @@ impl WhisperContract {
             }),
         );
 
-        Promise::new(to).transfer(amount)
+        self.record_value_transferred(&to, amount);
+        self.payment_transfer(to, amount)
     }
 
-    // ========================================================================
-    // Group Chats
-    // ========================================================================
-
+    /// Like `send_message_with_payment`, but the contract holds the attached
+    /// NEAR until the recipient claims it. If it is still unclaimed after
+    /// `ESCROW_TIMEOUT_NS`, the sender may take it back. With `require_ack`,
+    /// the recipient has to `acknowledge` the message before claiming.
     #[payable]
-    pub fn create_group(
+    pub fn send_message_escrow(
         &mut self,
-        group_id: String,
-        name: Option<String>,
-        member_keys: String,
-    ) {
-        let creator = env::predecessor_account_id();
-        let deposit = env::attached_deposit();
+        to: AccountId,
+        encrypted_body: String,
+        nonce: String,
+        recipient_key_version: u32,
+        reply_to: Option<String>,
+        require_ack: Option<bool>,
+    ) -> u64 {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+        let amount = env::attached_deposit();
+        let require_ack = require_ack.unwrap_or(false);
 
         assert!(
-            deposit >= NearToken::from_millinear(10),
-            "Attach at least 0.01 NEAR for storage"
+            amount >= MIN_ESCROW_AMOUNT,
+            "Escrow payment must be at least {}",
+            MIN_ESCROW_AMOUNT.exact_amount_display()
+        );
+        validate_ciphertext(&encrypted_body, &nonce);
+        self.validate_reply_to(&reply_to);
+        self.assert_can_send(&from, &to);
+        self.assert_accepts_payments(&to);
+        self.assert_payment_covers_fee(&from, &to, amount);
+        self.validate_recipient_key_version(&to, recipient_key_version);
+
+        let message_id = self.emit_message(
+            &from,
+            &to,
+            serde_json::json!({
+                "encrypted_body": encrypted_body,
+                "nonce": nonce,
+                "recipient_key_version": recipient_key_version,
+                "reply_to": reply_to,
+                "payment": {
+                    "token": "NEAR",
+                    "amount": amount.as_yoctonear().to_string(),
+                    "status": "escrow",
+                    "require_ack": require_ack,
+                }
+            }),
+        );
+
+        let escrow = Escrow {
+            from,
+            to,
+            amount,
+            created_at: env::block_timestamp(),
+            require_ack,
+            acknowledged: false,
+        };
+        self.escrows.insert(&message_id, &escrow);
+        self.held_balance = self.held_balance.saturating_add(amount);
+
+        message_id
+    }
+
+    /// Releases an escrowed payment to its recipient
+    pub fn claim_payment(&mut self, message_id: u64) -> Promise {
+        let escrow = self
+            .escrows
+            .get(&message_id)
+            .unwrap_or_else(|| env::panic_str("No escrow for this message"));
+        assert_eq!(
+            env::predecessor_account_id(),
+            escrow.to,
+            "Only the recipient can claim this payment"
         );
         assert!(
-            self.groups.get(&group_id).is_none(),
-            "Group ID already exists"
+            !escrow.require_ack || escrow.acknowledged,
+            "Acknowledge the message before claiming"
+        );
+        self.escrows.remove(&message_id);
+        self.held_balance = self.held_balance.saturating_sub(escrow.amount);
+        self.record_value_transferred(&escrow.to, escrow.amount);
+
+        self.emit_event(
+            "payment_claimed",
+            serde_json::json!({
+                "message_id": message_id,
+                "from": escrow.from.to_string(),
+                "to": escrow.to.to_string(),
+                "amount": escrow.amount.as_yoctonear().to_string(),
+            }),
+        );
+
+        self.payment_transfer(escrow.to, escrow.amount)
+    }
+
+    /// Confirms receipt of an escrow payment message, releasing it for
+    /// `claim_payment` when the sender required an acknowledgement
+    pub fn acknowledge(&mut self, message_id: u64) {
+        let mut escrow = self
+            .escrows
+            .get(&message_id)
+            .unwrap_or_else(|| env::panic_str("No escrow for this message"));
+        assert_eq!(
+            env::predecessor_account_id(),
+            escrow.to,
+            "Only the recipient can acknowledge this message"
+        );
+        assert!(!escrow.acknowledged, "Message is already acknowledged");
+        escrow.acknowledged = true;
+        self.escrows.insert(&message_id, &escrow);
+
+        self.emit_event(
+            "message_acknowledged",
+            serde_json::json!({
+                "message_id": message_id,
+                "from": escrow.from.to_string(),
+                "to": escrow.to.to_string(),
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Returns an unclaimed escrowed payment to its sender after the timeout
+    pub fn refund_payment(&mut self, message_id: u64) -> Promise {
+        let escrow = self
+            .escrows
+            .get(&message_id)
+            .unwrap_or_else(|| env::panic_str("No escrow for this message"));
+        assert_eq!(
+            env::predecessor_account_id(),
+            escrow.from,
+            "Only the sender can refund this payment"
+        );
+        assert!(
+            env::block_timestamp() >= escrow.created_at + ESCROW_TIMEOUT_NS,
+            "Escrow has not timed out yet"
+        );
+        self.escrows.remove(&message_id);
+        self.held_balance = self.held_balance.saturating_sub(escrow.amount);
+
+        self.emit_event(
+            "payment_refunded",
+            serde_json::json!({
+                "message_id": message_id,
+                "from": escrow.from.to_string(),
+                "to": escrow.to.to_string(),
+                "amount": escrow.amount.as_yoctonear().to_string(),
+            }),
+        );
+
+        self.payment_transfer(escrow.from, escrow.amount)
+    }
+
+    /// NEP-141 receiver: senders pay in fungible tokens by calling
+    /// `ft_transfer_call` on the token contract with an `FtMessage` as `msg`.
+    /// The tokens are forwarded to the recipient; anything that fails to
+    /// forward is reported back as unused so the token contract refunds it.
+    /// Only tokens the owner added with `add_ft_token` are accepted, since
+    /// the message is attributed to the `sender_id` the token reports.
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_contract = env::predecessor_account_id();
+        assert!(
+            self.ft_tokens.contains(&token_contract),
+            "Token is not accepted for payment messages"
+        );
+        let message: FtMessage = serde_json::from_str(&msg)
+            .unwrap_or_else(|_| env::panic_str("Invalid message in ft_transfer_call msg"));
+
+        let promise = self.send_message_with_ft(sender_id, message, token_contract, amount);
+        PromiseOrValue::Promise(promise)
+    }
+
+    /// The `message` event already announced the payment, so a failed
+    /// forward is followed by `ft_payment_failed` for indexers to undo it
+    #[private]
+    pub fn on_ft_payment_complete(
+        &mut self,
+        message_id: u64,
+        token_contract: AccountId,
+        from: AccountId,
+        to: AccountId,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> U128 {
+        if result.is_ok() {
+            log!("FT payment for message {} delivered", message_id);
+            return U128(0);
+        }
+
+        self.emit_event(
+            "ft_payment_failed",
+            serde_json::json!({
+                "message_id": message_id,
+                "token": token_contract.to_string(),
+                "from": from.to_string(),
+                "to": to.to_string(),
+                "amount": amount.0.to_string(),
+            }),
+        );
+        amount
+    }
+
+    fn send_message_with_ft(
+        &mut self,
+        from: AccountId,
+        message: FtMessage,
+        token_contract: AccountId,
+        amount: U128,
+    ) -> Promise {
+        self.assert_not_paused();
+        assert!(amount.0 > 0, "Must transfer tokens for payment message");
+        validate_ciphertext(&message.encrypted_body, &message.nonce);
+        self.validate_reply_to(&message.reply_to);
+        self.assert_can_send(&from, &message.to);
+        self.validate_recipient_key_version(&message.to, message.recipient_key_version);
+        self.assert_accepts_payments(&message.to);
+        assert!(
+            self.inbound_fee_for(&from, &message.to).is_none(),
+            "Recipient charges an inbound fee in NEAR"
+        );
+
+        let message_id = self.emit_message(
+            &from,
+            &message.to,
+            serde_json::json!({
+                "encrypted_body": message.encrypted_body,
+                "nonce": message.nonce,
+                "recipient_key_version": message.recipient_key_version,
+                "reply_to": message.reply_to,
+                "payment": {
+                    "token": token_contract.to_string(),
+                    "amount": amount.0.to_string(),
+                }
+            }),
+        );
+
+        let memo = Some(format!("whisper message {}", message_id));
+        ext_ft::ext(token_contract.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_FT_TRANSFER)
+            .ft_transfer(message.to.clone(), amount, memo)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_FT_CALLBACK)
+                    .on_ft_payment_complete(message_id, token_contract, from, message.to, amount),
+            )
+    }
+
+    /// Reacts to an earlier message. Reactions do not consume a message id;
+    /// the contract keeps a per-message tally, charging the caller for the
+    /// storage of reactions it hasn't seen on that message before.
+    #[payable]
+    pub fn react(&mut self, message_id: u64, to: AccountId, reaction: String) {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+
+        assert!(
+            !reaction.is_empty() && reaction.len() <= MAX_REACTION_LEN,
+            "Reaction must be 1-{} bytes",
+            MAX_REACTION_LEN
+        );
+        assert!(
+            message_id >= 1 && message_id <= self.message_count,
+            "Unknown message id"
+        );
+        // Reactions aren't messages, so they don't use up the inbound rate limit
+        self.assert_can_reach(&from, &to);
+
+        let storage_before = env::storage_usage();
+        let mut counts = self.reaction_counts.get(&message_id).unwrap_or_default();
+        assert!(
+            counts.contains_key(&reaction) || counts.len() < MAX_DISTINCT_REACTIONS,
+            "Message already has {} distinct reactions",
+            MAX_DISTINCT_REACTIONS
+        );
+        *counts.entry(reaction.clone()).or_insert(0) += 1;
+        self.reaction_counts.insert(&message_id, &counts);
+        self.charge_storage(&from, storage_before, NearToken::from_yoctonear(0));
+
+        self.emit_event(
+            "reaction",
+            serde_json::json!({
+                "from": from.to_string(),
+                "to": to.to_string(),
+                "message_id": message_id,
+                "reaction": reaction,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Ephemeral typing indicator; emits an event and nothing else
+    pub fn typing(&mut self, to: AccountId) {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+        self.assert_can_reach(&from, &to);
+
+        self.emit_event(
+            "typing",
+            serde_json::json!({
+                "from": from.to_string(),
+                "to": to.to_string(),
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Advisory "unsend". Messages aren't stored, so the contract can't check
+    /// who sent `message_id`; the event carries the caller so clients can
+    /// match it against the original sender before hiding the message.
+    pub fn delete_message(&mut self, message_id: u64, to: AccountId) {
+        self.assert_not_paused();
+        assert!(
+            message_id >= 1 && message_id <= self.message_count,
+            "Unknown message id"
+        );
+
+        self.emit_event(
+            "message_deleted",
+            serde_json::json!({
+                "id": message_id,
+                "to": to.to_string(),
+                "deleted_by": env::predecessor_account_id().to_string(),
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Opt-in read receipts for messages received from `from`
+    pub fn mark_read(&mut self, message_ids: Vec<u64>, from: AccountId) {
+        self.assert_not_paused();
+        let reader = env::predecessor_account_id();
+
+        assert!(!message_ids.is_empty(), "No message ids given");
+        assert!(
+            message_ids.len() <= MAX_READ_BATCH,
+            "Cannot mark more than {} messages at once",
+            MAX_READ_BATCH
         );
 
-        let group = GroupChat {
-            group_id: group_id.clone(),
-            creator: creator.clone(),
-            created_at: env::block_timestamp(),
-            name: name.clone(),
-        };
+        self.emit_event(
+            "messages_read",
+            serde_json::json!({
+                "reader": reader.to_string(),
+                "from": from.to_string(),
+                "message_ids": message_ids,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    // ========================================================================
+    // Scheduled Messages
+    // ========================================================================
+
+    /// Stores a message for delivery at `deliver_at`. The contract can't
+    /// wake itself, so a cron account has to call `deliver_scheduled` once
+    /// it is due. Storage is charged now and refunded on delivery.
+    #[payable]
+    pub fn schedule_message(
+        &mut self,
+        to: AccountId,
+        encrypted_body: String,
+        nonce: String,
+        recipient_key_version: u32,
+        deliver_at: u64,
+    ) -> u64 {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+
+        validate_ciphertext(&encrypted_body, &nonce);
+        assert!(
+            deliver_at > env::block_timestamp(),
+            "deliver_at must be in the future"
+        );
+        self.assert_can_schedule(&from, &to, recipient_key_version);
+
+        self.scheduled_count += 1;
+        let schedule_id = self.scheduled_count;
+        let storage_before = env::storage_usage();
+        let mut pending = PendingMessage {
+            from: from.clone(),
+            to: to.clone(),
+            encrypted_body,
+            nonce,
+            recipient_key_version,
+            deliver_at,
+            storage_paid: NearToken::from_yoctonear(0),
+        };
+        self.pending_messages.insert(&schedule_id, &pending);
+        pending.storage_paid =
+            self.charge_storage(&from, storage_before, NearToken::from_yoctonear(0));
+        self.pending_messages.insert(&schedule_id, &pending);
+
+        self.emit_event(
+            "message_scheduled",
+            serde_json::json!({
+                "schedule_id": schedule_id,
+                "from": from.to_string(),
+                "to": to.to_string(),
+                "deliver_at": deliver_at,
+            }),
+        );
+        schedule_id
+    }
+
+    /// Publishes a due scheduled message as a regular `message` event. Anyone
+    /// may call it; sending rules are checked again at delivery.
+    pub fn deliver_scheduled(&mut self, schedule_id: u64) -> u64 {
+        self.assert_not_paused();
+        let pending = self
+            .pending_messages
+            .get(&schedule_id)
+            .unwrap_or_else(|| env::panic_str("Scheduled message does not exist"));
+        assert!(
+            env::block_timestamp() >= pending.deliver_at,
+            "Scheduled message is not due yet"
+        );
+        self.assert_can_schedule(&pending.from, &pending.to, pending.recipient_key_version);
+        self.pending_messages.remove(&schedule_id);
+
+        let message_id = self.emit_message(
+            &pending.from,
+            &pending.to,
+            serde_json::json!({
+                "encrypted_body": pending.encrypted_body,
+                "nonce": pending.nonce,
+                "recipient_key_version": pending.recipient_key_version,
+                "schedule_id": schedule_id,
+            }),
+        );
+        if !pending.storage_paid.is_zero() {
+            Promise::new(pending.from).transfer(pending.storage_paid);
+        }
+        message_id
+    }
+
+    /// Drops one of the caller's scheduled messages and refunds its storage
+    pub fn cancel_scheduled(&mut self, schedule_id: u64) {
+        let from = env::predecessor_account_id();
+        let pending = self
+            .pending_messages
+            .get(&schedule_id)
+            .unwrap_or_else(|| env::panic_str("Scheduled message does not exist"));
+        assert_eq!(pending.from, from, "Only the sender can cancel");
+        self.pending_messages.remove(&schedule_id);
+
+        if !pending.storage_paid.is_zero() {
+            Promise::new(from.clone()).transfer(pending.storage_paid);
+        }
+        self.emit_event(
+            "message_schedule_cancelled",
+            serde_json::json!({
+                "schedule_id": schedule_id,
+                "from": from.to_string(),
+            }),
+        );
+    }
+
+    // ========================================================================
+    // Block List
+    // ========================================================================
+
+    /// Blocks `account_id`, charging the entry's storage to the caller
+    #[payable]
+    pub fn block(&mut self, account_id: AccountId) {
+        let owner = env::predecessor_account_id();
+
+        assert!(account_id != owner, "Cannot block yourself");
+        let storage_before = env::storage_usage();
+        assert!(
+            self.blocked.insert(&(owner.clone(), account_id.clone())),
+            "Account is already blocked"
+        );
+        self.charge_storage(&owner, storage_before, NearToken::from_yoctonear(0));
+
+        self.emit_event(
+            "account_blocked",
+            serde_json::json!({
+                "owner": owner.to_string(),
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    /// Unblocks `account_id` and refunds the entry's storage
+    pub fn unblock(&mut self, account_id: AccountId) {
+        let owner = env::predecessor_account_id();
+
+        let storage_before = env::storage_usage();
+        assert!(
+            self.blocked.remove(&(owner.clone(), account_id.clone())),
+            "Account is not blocked"
+        );
+        self.refund_storage(&owner, storage_before);
+
+        self.emit_event(
+            "account_unblocked",
+            serde_json::json!({
+                "owner": owner.to_string(),
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    // ========================================================================
+    // Contacts
+    // ========================================================================
+
+    pub fn set_contacts_only(&mut self, contacts_only: bool) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        profile.contacts_only = contacts_only;
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "contacts_only_changed",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "contacts_only": contacts_only,
+            }),
+        );
+    }
+
+    /// Adds a contact, charging its measured storage and refunding the rest
+    /// of the deposit
+    #[payable]
+    pub fn add_contact(&mut self, account_id: AccountId) {
+        let owner = env::predecessor_account_id();
+
+        let storage_before = env::storage_usage();
+        assert!(
+            self.insert_contact(&owner, &account_id),
+            "Account is already a contact"
+        );
+        self.charge_storage(&owner, storage_before, NearToken::from_yoctonear(0));
+
+        self.emit_event(
+            "contact_added",
+            serde_json::json!({
+                "owner": owner.to_string(),
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    /// Removes a contact and refunds the storage it freed
+    pub fn remove_contact(&mut self, account_id: AccountId) {
+        let owner = env::predecessor_account_id();
+
+        let storage_before = env::storage_usage();
+        assert!(
+            self.contacts.remove(&(owner.clone(), account_id.clone())),
+            "Account is not a contact"
+        );
+        let mut list = self.contact_lists.get(&owner).unwrap_or_default();
+        list.retain(|contact| *contact != account_id);
+        if list.is_empty() {
+            self.contact_lists.remove(&owner);
+        } else {
+            self.contact_lists.insert(&owner, &list);
+        }
+        self.refund_storage(&owner, storage_before);
+
+        self.emit_event(
+            "contact_removed",
+            serde_json::json!({
+                "owner": owner.to_string(),
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    /// Adds several contacts, charging the storage the batch adds. Existing
+    /// contacts are skipped and the deposit left over is refunded.
+    #[payable]
+    pub fn add_contacts(&mut self, account_ids: Vec<AccountId>) {
+        let owner = env::predecessor_account_id();
+        assert!(
+            account_ids.len() <= MAX_CONTACTS_PER_CALL,
+            "At most {} contacts per call",
+            MAX_CONTACTS_PER_CALL
+        );
+
+        let storage_before = env::storage_usage();
+        let added: Vec<AccountId> = account_ids
+            .into_iter()
+            .filter(|account_id| self.insert_contact(&owner, account_id))
+            .collect();
+        self.charge_storage(&owner, storage_before, NearToken::from_yoctonear(0));
+
+        self.emit_event(
+            "contacts_added",
+            serde_json::json!({
+                "owner": owner.to_string(),
+                "account_ids": added.iter().map(|a| a.to_string()).collect::<Vec<_>>(),
+            }),
+        );
+    }
+
+    /// Removes every contact and refunds the storage they freed
+    pub fn clear_contacts(&mut self) {
+        let owner = env::predecessor_account_id();
+        let storage_before = env::storage_usage();
+        let list = self.contact_lists.remove(&owner).unwrap_or_default();
+        for account_id in &list {
+            self.contacts.remove(&(owner.clone(), account_id.clone()));
+        }
+        self.refund_storage(&owner, storage_before);
+
+        self.emit_event(
+            "contacts_cleared",
+            serde_json::json!({
+                "owner": owner.to_string(),
+                "count": list.len(),
+            }),
+        );
+    }
+
+    // ========================================================================
+    // Inbound Fees
+    // ========================================================================
+
+    /// Charges non-contacts `amount` per message; `None` removes the fee
+    pub fn set_inbound_fee(&mut self, amount: Option<NearToken>) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        profile.inbound_fee = amount.filter(|fee| !fee.is_zero());
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "inbound_fee_changed",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "inbound_fee": profile.inbound_fee.map(|fee| fee.as_yoctonear().to_string()),
+            }),
+        );
+    }
+
+    /// Caps how many direct messages, from any send method, the caller
+    /// receives per window. A `per_window` of 0 removes the limit.
+    ///
+    /// The caller pays for the window's storage up front, so senders never
+    /// grow state on their behalf; removing the limit refunds it.
+    #[payable]
+    pub fn set_inbound_rate_limit(&mut self, per_window: u32, window_seconds: u64) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        let storage_before = env::storage_usage();
+        profile.inbound_rate_limit = rate_limit(per_window, window_seconds);
+        self.profiles.insert(&account_id, &profile);
+        if profile.inbound_rate_limit.is_none() {
+            self.inbound_windows.remove(&account_id);
+        } else if self.inbound_windows.get(&account_id).is_none() {
+            self.inbound_windows.insert(&account_id, &(env::block_timestamp(), 0));
+        }
+
+        let storage_cost =
+            self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+        let refund = self.refund_storage(&account_id, storage_before);
+        profile.storage_paid = profile
+            .storage_paid
+            .saturating_add(storage_cost)
+            .saturating_sub(refund);
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "inbound_rate_limit_changed",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "inbound_rate_limit": profile.inbound_rate_limit,
+            }),
+        );
+    }
+
+    /// Opts the caller in or out of payment messages of any kind
+    pub fn set_accepts_payments(&mut self, accepts_payments: bool) {
+        let account_id = env::predecessor_account_id();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        profile.accepts_payments = accepts_payments;
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "accepts_payments_changed",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "accepts_payments": accepts_payments,
+            }),
+        );
+    }
+
+    // ========================================================================
+    // Group Chats
+    // ========================================================================
+
+    #[payable]
+    pub fn create_group(
+        &mut self,
+        group_id: String,
+        name: Option<String>,
+        member_keys: String,
+        is_open: Option<bool>,
+    ) {
+        self.assert_not_paused();
+        let creator = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let is_open = is_open.unwrap_or(false);
+        validate_id("Group", &group_id);
+
+        let mut members = vec![creator.clone()];
+        for member in parse_member_keys(&member_keys) {
+            if !members.contains(&member) {
+                members.push(member);
+            }
+        }
+        assert!(members.len() as u32 <= MAX_GROUP_MEMBERS, "Group is full");
+
+        let required = self
+            .group_deposit
+            .saturating_add(GROUP_MEMBER_DEPOSIT.saturating_mul(members.len() as u128));
+        assert!(
+            deposit >= required,
+            "Attach at least {} yoctoNEAR for storage",
+            required.as_yoctonear()
+        );
+        assert!(
+            self.groups.get(&group_id).is_none(),
+            "Group ID already exists"
+        );
+
+        let group = GroupChat {
+            group_id: group_id.clone(),
+            creator: creator.clone(),
+            created_at: env::block_timestamp(),
+            name: name.clone(),
+            member_count: members.len() as u32,
+            members,
+            group_key_version: 1,
+            message_count: 0,
+            is_open,
+            admins: Vec::new(),
+            pin: None,
+            rate_limit: None,
+            deposit: required,
+        };
+
+        self.groups.insert(&group_id, &group);
+        let mut created = self.groups_by_creator.get(&creator).unwrap_or_default();
+        created.push(group_id.clone());
+        self.groups_by_creator.insert(&creator, &created);
+
+        self.emit_event(
+            "group_created",
+            serde_json::json!({
+                "group_id": group_id,
+                "creator": creator.to_string(),
+                "name": name,
+                "member_keys": member_keys,
+                "is_open": is_open,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Under a group rate limit, the sender's first message pays for their
+    /// rate window's storage; the rest of the deposit is refunded.
+    #[payable]
+    pub fn send_group_message(
+        &mut self,
+        group_id: String,
+        encrypted_body: String,
+        nonce: String,
+        group_key_version: u32,
+        reply_to: Option<String>,
+        client_version: Option<String>,
+        attachment_ref: Option<String>,
+        attachment_size: Option<u64>,
+        compression: Option<String>,
+    ) -> u64 {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+
+        let mut group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        assert!(group.members.contains(&from), "Not a group member");
+        // Fail up front instead of running out of gas halfway through
+        assert!(
+            env::prepaid_gas() >= group_message_gas(&group),
+            "Insufficient gas for group of {} members",
+            group.member_count
+        );
+        validate_ciphertext(&encrypted_body, &nonce);
+        self.validate_reply_to(&reply_to);
+        validate_client_version(&client_version);
+        validate_compression(&compression);
+        // Attachments live off-chain; the size lets clients show it before fetching
+        if let Some(attachment_ref) = &attachment_ref {
+            assert!(
+                !attachment_ref.is_empty() && attachment_ref.len() <= MAX_ATTACHMENT_REF_LEN,
+                "attachment_ref must be 1-{} bytes",
+                MAX_ATTACHMENT_REF_LEN
+            );
+        }
+        assert!(
+            attachment_size.is_none() || attachment_ref.is_some(),
+            "attachment_size requires an attachment_ref"
+        );
+        let storage_before = env::storage_usage();
+        self.consume_group_quota(&group, &from);
+        self.charge_storage(&from, storage_before, NearToken::from_yoctonear(0));
+        if group_key_version < group.group_key_version {
+            log!(
+                "Group message uses stale key version {} (current {})",
+                group_key_version,
+                group.group_key_version
+            );
+        }
+
+        self.message_count += 1;
+        let message_id = self.message_count;
+        self.group_message_count += 1;
+        self.increment_sent_count(&from);
+        group.message_count += 1;
+        self.groups.insert(&group_id, &group);
+
+        self.emit_event(
+            "group_message",
+            serde_json::json!({
+                "id": message_id,
+                "group_id": group_id,
+                "from": from.to_string(),
+                "encrypted_body": encrypted_body,
+                "nonce": nonce,
+                "group_key_version": group_key_version,
+                "reply_to": reply_to,
+                "client_version": client_version,
+                "attachment_ref": attachment_ref,
+                "attachment_size": attachment_size,
+                "compression": compression,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+
+        message_id
+    }
+
+    #[payable]
+    pub fn add_group_member(
+        &mut self,
+        group_id: String,
+        account_id: AccountId,
+        encrypted_group_key: String,
+    ) {
+        let mut group = self.get_group_as_admin(&group_id);
+        assert!(
+            env::attached_deposit() >= GROUP_MEMBER_DEPOSIT,
+            "Attach at least {} yoctoNEAR for storage",
+            GROUP_MEMBER_DEPOSIT.as_yoctonear()
+        );
+        assert!(!group.members.contains(&account_id), "Already a group member");
+        assert!(group.member_count < MAX_GROUP_MEMBERS, "Group is full");
+
+        group.members.push(account_id.clone());
+        group.member_count += 1;
+        group.deposit = group.deposit.saturating_add(GROUP_MEMBER_DEPOSIT);
+        self.groups.insert(&group_id, &group);
+
+        self.emit_event(
+            "group_member_added",
+            serde_json::json!({
+                "group_id": group_id,
+                "account_id": account_id.to_string(),
+                "encrypted_group_key": encrypted_group_key,
+                "group_key_version": group.group_key_version,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Asks the creator to add the caller; approval happens off-chain or
+    /// through `add_group_member`
+    pub fn request_join(&mut self, group_id: String) {
+        let account_id = env::predecessor_account_id();
+        let group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        assert!(!group.members.contains(&account_id), "Already a group member");
+
+        self.emit_event(
+            "group_join_request",
+            serde_json::json!({
+                "group_id": group_id,
+                "account_id": account_id.to_string(),
+                "creator": group.creator.to_string(),
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Self-adds the caller to an open group. Any deposit beyond
+    /// `GROUP_MEMBER_DEPOSIT` is refunded.
+    #[payable]
+    pub fn join_group(&mut self, group_id: String, encrypted_group_key: String) {
+        self.assert_not_paused();
+        let account_id = env::predecessor_account_id();
+        let mut group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        assert!(group.is_open, "Group is not open; use request_join");
+        assert!(
+            env::attached_deposit() >= GROUP_MEMBER_DEPOSIT,
+            "Attach at least {} yoctoNEAR for storage",
+            GROUP_MEMBER_DEPOSIT.as_yoctonear()
+        );
+        assert!(!group.members.contains(&account_id), "Already a group member");
+        assert!(group.member_count < MAX_GROUP_MEMBERS, "Group is full");
+
+        group.members.push(account_id.clone());
+        group.member_count += 1;
+        group.deposit = group.deposit.saturating_add(GROUP_MEMBER_DEPOSIT);
+        self.groups.insert(&group_id, &group);
+
+        let excess = env::attached_deposit().saturating_sub(GROUP_MEMBER_DEPOSIT);
+        if !excess.is_zero() {
+            Promise::new(account_id.clone()).transfer(excess);
+        }
+
+        self.emit_event(
+            "group_member_added",
+            serde_json::json!({
+                "group_id": group_id,
+                "account_id": account_id.to_string(),
+                "encrypted_group_key": encrypted_group_key,
+                "group_key_version": group.group_key_version,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Creates a single-use invite into `group_id` valid until `expires_at`
+    /// and returns its id, which is unguessable so it can be shared as a link.
+    #[payable]
+    pub fn create_group_invite(&mut self, group_id: String, expires_at: u64) -> String {
+        let group = self.get_group_as_admin(&group_id);
+        assert!(
+            env::attached_deposit() >= GROUP_INVITE_DEPOSIT,
+            "Attach at least {} yoctoNEAR for storage",
+            GROUP_INVITE_DEPOSIT.as_yoctonear()
+        );
+        assert!(
+            expires_at > env::block_timestamp(),
+            "expires_at must be in the future"
+        );
+
+        self.invite_count += 1;
+        let mut seed = env::random_seed();
+        seed.extend_from_slice(&self.invite_count.to_le_bytes());
+        let invite_id = near_sdk::bs58::encode(&env::sha256(&seed)[..16]).into_string();
+
+        let created_by = env::predecessor_account_id();
+        self.group_invites.insert(
+            &invite_id,
+            &GroupInvite {
+                group_id: group.group_id,
+                created_by: created_by.clone(),
+                expires_at,
+                accepted_by: None,
+            },
+        );
+
+        self.emit_event(
+            "group_invite_created",
+            serde_json::json!({
+                "invite_id": invite_id,
+                "group_id": group_id,
+                "created_by": created_by.to_string(),
+                "expires_at": expires_at,
+            }),
+        );
+
+        invite_id
+    }
+
+    /// Self-adds the caller to the invite's group and consumes the invite
+    #[payable]
+    pub fn accept_group_invite(&mut self, invite_id: String, encrypted_group_key: String) {
+        let account_id = env::predecessor_account_id();
+        let mut invite = self
+            .group_invites
+            .get(&invite_id)
+            .unwrap_or_else(|| env::panic_str("Invite does not exist"));
+        assert!(invite.accepted_by.is_none(), "Invite has already been used");
+        assert!(
+            env::block_timestamp() < invite.expires_at,
+            "Invite has expired"
+        );
+        let mut group = self
+            .groups
+            .get(&invite.group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        assert!(
+            env::attached_deposit() >= GROUP_MEMBER_DEPOSIT,
+            "Attach at least {} yoctoNEAR for storage",
+            GROUP_MEMBER_DEPOSIT.as_yoctonear()
+        );
+        assert!(!group.members.contains(&account_id), "Already a group member");
+        assert!(group.member_count < MAX_GROUP_MEMBERS, "Group is full");
+
+        group.members.push(account_id.clone());
+        group.member_count += 1;
+        group.deposit = group.deposit.saturating_add(GROUP_MEMBER_DEPOSIT);
+        self.groups.insert(&invite.group_id, &group);
+        invite.accepted_by = Some(account_id.clone());
+        self.group_invites.insert(&invite_id, &invite);
+
+        self.emit_event(
+            "group_member_added",
+            serde_json::json!({
+                "group_id": invite.group_id,
+                "account_id": account_id.to_string(),
+                "encrypted_group_key": encrypted_group_key,
+                "group_key_version": group.group_key_version,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+        self.emit_event(
+            "group_invite_accepted",
+            serde_json::json!({
+                "invite_id": invite_id,
+                "group_id": invite.group_id,
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    /// Records the last group message the caller has read, so clients can
+    /// show unread counts across devices. The first cursor's storage is
+    /// charged to the caller.
+    #[payable]
+    pub fn set_group_read_cursor(&mut self, group_id: String, last_message_id: u64) {
+        let account_id = env::predecessor_account_id();
+        let group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        assert!(group.members.contains(&account_id), "Not a group member");
+        assert!(last_message_id <= self.message_count, "Unknown message id");
+
+        let storage_before = env::storage_usage();
+        self.group_read_cursors
+            .insert(&(group_id, account_id.clone()), &last_message_id);
+        self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+    }
+
+    /// Notification preference synced across the caller's devices; the
+    /// contract itself never notifies anyone. The first call's storage is
+    /// charged to the caller.
+    #[payable]
+    pub fn set_group_muted(&mut self, group_id: String, muted: bool) {
+        let account_id = env::predecessor_account_id();
+        let group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        assert!(group.members.contains(&account_id), "Not a group member");
+
+        // Unmuting keeps the entry, so toggling back doesn't pay again
+        let storage_before = env::storage_usage();
+        self.group_mutes
+            .insert(&(group_id, account_id.clone()), &muted);
+        self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+    }
+
+    /// Refunds the removed member's `GROUP_MEMBER_DEPOSIT` to the caller
+    pub fn remove_group_member(&mut self, group_id: String, account_id: AccountId) {
+        let mut group = self.get_group_as_admin(&group_id);
+        assert!(account_id != group.creator, "Cannot remove the group creator");
+        if group.admins.contains(&account_id) {
+            assert_eq!(
+                env::predecessor_account_id(),
+                group.creator,
+                "Only the group creator can remove an admin"
+            );
+            group.admins.retain(|admin| *admin != account_id);
+        }
+
+        let position = group
+            .members
+            .iter()
+            .position(|m| *m == account_id)
+            .unwrap_or_else(|| env::panic_str("Not a group member"));
+        group.members.remove(position);
+        group.member_count -= 1;
+        group.group_key_version += 1;
+        let refund = GROUP_MEMBER_DEPOSIT.min(group.deposit);
+        group.deposit = group.deposit.saturating_sub(refund);
+        self.groups.insert(&group_id, &group);
+        if !refund.is_zero() {
+            Promise::new(env::predecessor_account_id()).transfer(refund);
+        }
+
+        self.emit_event(
+            "group_member_removed",
+            serde_json::json!({
+                "group_id": group_id,
+                "account_id": account_id.to_string(),
+                "group_key_version": group.group_key_version,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Lets an existing member manage membership and keys alongside the creator
+    pub fn add_group_admin(&mut self, group_id: String, account_id: AccountId) {
+        let mut group = self.get_group_as_creator(&group_id);
+        assert!(group.members.contains(&account_id), "Not a group member");
+        assert!(
+            account_id != group.creator && !group.admins.contains(&account_id),
+            "Already a group admin"
+        );
+
+        group.admins.push(account_id.clone());
+        self.groups.insert(&group_id, &group);
+
+        self.emit_event(
+            "group_admin_added",
+            serde_json::json!({
+                "group_id": group_id,
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    pub fn remove_group_admin(&mut self, group_id: String, account_id: AccountId) {
+        let mut group = self.get_group_as_creator(&group_id);
+        assert!(group.admins.contains(&account_id), "Not a group admin");
+
+        group.admins.retain(|admin| *admin != account_id);
+        self.groups.insert(&group_id, &group);
+
+        self.emit_event(
+            "group_admin_removed",
+            serde_json::json!({
+                "group_id": group_id,
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    /// Publishes freshly wrapped group keys after membership changes.
+    pub fn rotate_group_key(
+        &mut self,
+        group_id: String,
+        member_keys: String,
+        new_key_version: u32,
+    ) {
+        let mut group = self.get_group_as_admin(&group_id);
+        parse_member_keys(&member_keys);
+        assert!(
+            new_key_version > group.group_key_version,
+            "New key version must be greater than {}",
+            group.group_key_version
+        );
+
+        group.group_key_version = new_key_version;
+        self.groups.insert(&group_id, &group);
+
+        self.emit_event(
+            "group_key_rotated",
+            serde_json::json!({
+                "group_id": group_id,
+                "group_key_version": new_key_version,
+                "member_keys": member_keys,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Pins an encrypted message for the group; an empty body clears the pin.
+    /// Storage growth is charged to the caller.
+    #[payable]
+    pub fn set_group_pin(
+        &mut self,
+        group_id: String,
+        encrypted_body: String,
+        nonce: String,
+        group_key_version: u32,
+    ) {
+        let mut group = self.get_group_as_admin(&group_id);
+        let account_id = env::predecessor_account_id();
+        let storage_before = env::storage_usage();
+
+        group.pin = if encrypted_body.is_empty() {
+            None
+        } else {
+            validate_ciphertext(&encrypted_body, &nonce);
+            Some(GroupPin {
+                encrypted_body,
+                nonce,
+                group_key_version,
+                pinned_by: account_id.clone(),
+                updated_at: env::block_timestamp(),
+            })
+        };
+        self.groups.insert(&group_id, &group);
+        self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+
+        self.emit_event(
+            "group_pin_updated",
+            serde_json::json!({
+                "group_id": group_id,
+                "pinned_by": account_id.to_string(),
+                "encrypted_body": group.pin.as_ref().map(|pin| &pin.encrypted_body),
+                "nonce": group.pin.as_ref().map(|pin| &pin.nonce),
+                "group_key_version": group_key_version,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Caps how many messages each member may post per window. A
+    /// `per_window` of 0 removes the limit.
+    pub fn set_group_rate_limit(&mut self, group_id: String, per_window: u32, window_seconds: u64) {
+        let mut group = self.get_group_as_admin(&group_id);
+        group.rate_limit = rate_limit(per_window, window_seconds);
+        self.groups.insert(&group_id, &group);
+
+        self.emit_event(
+            "group_rate_limit_changed",
+            serde_json::json!({
+                "group_id": group_id,
+                "rate_limit": group.rate_limit,
+            }),
+        );
+    }
+
+    pub fn rename_group(&mut self, group_id: String, name: Option<String>) {
+        let mut group = self.get_group_as_creator(&group_id);
+        group.name = name.clone();
+        self.groups.insert(&group_id, &group);
+
+        self.emit_event(
+            "group_renamed",
+            serde_json::json!({
+                "group_id": group_id,
+                "name": name,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Refunds the deposits still held for the group to its creator
+    pub fn delete_group(&mut self, group_id: String) {
+        let group = self.get_group_as_creator(&group_id);
+        self.groups.remove(&group_id);
+        if !group.deposit.is_zero() {
+            Promise::new(group.creator.clone()).transfer(group.deposit);
+        }
+
+        let mut created = self.groups_by_creator.get(&group.creator).unwrap_or_default();
+        created.retain(|id| *id != group_id);
+        if created.is_empty() {
+            self.groups_by_creator.remove(&group.creator);
+        } else {
+            self.groups_by_creator.insert(&group.creator, &created);
+        }
+
+        self.emit_event(
+            "group_deleted",
+            serde_json::json!({
+                "group_id": group_id,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    // ========================================================================
+    // Channels
+    // ========================================================================
+
+    #[payable]
+    pub fn create_channel(&mut self, channel_id: String, name: Option<String>) {
+        self.assert_not_paused();
+        let creator = env::predecessor_account_id();
+        validate_id("Channel", &channel_id);
+        assert!(
+            env::attached_deposit() >= CHANNEL_DEPOSIT,
+            "Attach at least {} yoctoNEAR for storage",
+            CHANNEL_DEPOSIT.as_yoctonear()
+        );
+        assert!(
+            self.channels.get(&channel_id).is_none(),
+            "Channel ID already exists"
+        );
+
+        let channel = Channel {
+            channel_id: channel_id.clone(),
+            creator: creator.clone(),
+            created_at: env::block_timestamp(),
+            name: name.clone(),
+            subscriber_count: 0,
+        };
+        self.channels.insert(&channel_id, &channel);
+
+        self.emit_event(
+            "channel_created",
+            serde_json::json!({
+                "channel_id": channel_id,
+                "creator": creator.to_string(),
+                "name": name,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    pub fn broadcast(&mut self, channel_id: String, encrypted_body: String, nonce: String) -> u64 {
+        self.assert_not_paused();
+        let from = env::predecessor_account_id();
+
+        let channel = self
+            .channels
+            .get(&channel_id)
+            .unwrap_or_else(|| env::panic_str("Channel does not exist"));
+        assert_eq!(from, channel.creator, "Only the channel creator can broadcast");
+        validate_ciphertext(&encrypted_body, &nonce);
+
+        self.message_count += 1;
+        let message_id = self.message_count;
+        self.increment_sent_count(&from);
+
+        self.emit_event(
+            "broadcast",
+            serde_json::json!({
+                "id": message_id,
+                "channel_id": channel_id,
+                "from": from.to_string(),
+                "encrypted_body": encrypted_body,
+                "nonce": nonce,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+
+        message_id
+    }
+
+    /// Subscribes the caller, charging the subscription's measured storage
+    #[payable]
+    pub fn subscribe(&mut self, channel_id: String) {
+        let account_id = env::predecessor_account_id();
+
+        let mut channel = self
+            .channels
+            .get(&channel_id)
+            .unwrap_or_else(|| env::panic_str("Channel does not exist"));
+        let storage_before = env::storage_usage();
+        assert!(
+            self.channel_subscribers
+                .insert(&(channel_id.clone(), account_id.clone())),
+            "Already subscribed"
+        );
+        channel.subscriber_count += 1;
+        self.channels.insert(&channel_id, &channel);
+        self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+
+        self.emit_event(
+            "channel_subscribed",
+            serde_json::json!({
+                "channel_id": channel_id,
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    /// Unsubscribes the caller and refunds the subscription's storage
+    pub fn unsubscribe(&mut self, channel_id: String) {
+        let account_id = env::predecessor_account_id();
+
+        let storage_before = env::storage_usage();
+        assert!(
+            self.channel_subscribers
+                .remove(&(channel_id.clone(), account_id.clone())),
+            "Not subscribed"
+        );
+        if let Some(mut channel) = self.channels.get(&channel_id) {
+            channel.subscriber_count -= 1;
+            self.channels.insert(&channel_id, &channel);
+        }
+        self.refund_storage(&account_id, storage_before);
+
+        self.emit_event(
+            "channel_unsubscribed",
+            serde_json::json!({
+                "channel_id": channel_id,
+                "account_id": account_id.to_string(),
+            }),
+        );
+    }
+
+    // ========================================================================
+    // Admin
+    // ========================================================================
+
+    pub fn transfer_ownership(&mut self, new_owner: AccountId) {
+        self.assert_owner();
+
+        let old_owner = std::mem::replace(&mut self.owner, new_owner.clone());
+
+        self.emit_event(
+            "ownership_transferred",
+            serde_json::json!({
+                "old_owner": old_owner.to_string(),
+                "new_owner": new_owner.to_string(),
+            }),
+        );
+    }
+
+    /// Halts message emission and group creation; key management and views
+    /// keep working so users can rotate keys during an incident.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused = paused;
+
+        self.emit_event("paused_changed", serde_json::json!({ "paused": paused }));
+    }
+
+    /// When set, `register_key` rejects registrations without a key proof.
+    pub fn set_require_key_proof(&mut self, required: bool) {
+        self.assert_owner();
+        self.require_key_proof = required;
+    }
+
+    /// When set, `reply_to` must be the id of an already emitted message.
+    pub fn set_strict_reply_validation(&mut self, strict: bool) {
+        self.assert_owner();
+        self.strict_reply_validation = strict;
+    }
+
+    /// When set, `recipient_key_version` may not be older than the
+    /// recipient's current key.
+    pub fn set_strict_key_version(&mut self, strict: bool) {
+        self.assert_owner();
+        self.strict_key_version = strict;
+    }
+
+    pub fn set_allow_self_message(&mut self, allow: bool) {
+        self.assert_owner();
+        self.allow_self_message = allow;
+    }
+
+    pub fn set_verified(&mut self, account_id: AccountId, verified: bool) {
+        self.assert_owner();
+
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        profile.verified = verified;
+        self.profiles.insert(&account_id, &profile);
+
+        self.emit_event(
+            "verification_changed",
+            serde_json::json!({
+                "account_id": account_id.to_string(),
+                "verified": verified,
+            }),
+        );
+    }
+
+    /// Restricts `register_key` to allowlisted accounts, e.g. during a beta
+    pub fn set_allowlist_enabled(&mut self, enabled: bool) {
+        self.assert_owner();
+        self.allowlist_enabled = enabled;
+    }
+
+    pub fn add_to_allowlist(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.allowlist.insert(&account_id);
+    }
+
+    pub fn remove_from_allowlist(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.allowlist.remove(&account_id);
+    }
+
+    /// Trusts `token_contract` to call `ft_on_transfer` with the real sender
+    pub fn add_ft_token(&mut self, token_contract: AccountId) {
+        self.assert_owner();
+        self.ft_tokens.insert(&token_contract);
+    }
+
+    pub fn remove_ft_token(&mut self, token_contract: AccountId) {
+        self.assert_owner();
+        self.ft_tokens.remove(&token_contract);
+    }
+
+    pub fn set_min_display_name_len(&mut self, min_len: u8) {
+        self.assert_owner();
+        self.min_display_name_len = min_len;
+    }
+
+    /// Rejects new display names containing `substring`, case-insensitively
+    pub fn add_banned_substring(&mut self, substring: String) {
+        self.assert_owner();
+        assert!(!substring.is_empty(), "Banned substring must not be empty");
+        assert!(
+            self.banned_substrings.len() < MAX_BANNED_SUBSTRINGS,
+            "At most {} banned substrings",
+            MAX_BANNED_SUBSTRINGS
+        );
+        self.banned_substrings.insert(&substring.to_lowercase());
+    }
+
+    pub fn remove_banned_substring(&mut self, substring: String) {
+        self.assert_owner();
+        self.banned_substrings.remove(&substring.to_lowercase());
+    }
+
+    /// Recovers NEAR stranded in the contract, e.g. deposits left behind by
+    /// mistaken transfers. The storage staking reserve and `held_balance`
+    /// stay untouched.
+    pub fn withdraw(&mut self, amount: NearToken, to: AccountId) -> Promise {
+        self.assert_owner();
+
+        let withdrawable = self.withdrawable_balance();
+        assert!(
+            amount <= withdrawable,
+            "Amount exceeds withdrawable balance of {} yoctoNEAR",
+            withdrawable.as_yoctonear()
+        );
+
+        self.emit_event(
+            "withdrawal",
+            serde_json::json!({
+                "amount": amount.as_yoctonear().to_string(),
+                "to": to.to_string(),
+            }),
+        );
+
+        Promise::new(to).transfer(amount)
+    }
+
+    /// Removes profiles that have neither registered, rotated nor sent a
+    /// message within the prune threshold, refunding their storage
+    /// deposits. Any recently used profile aborts the whole call.
+    pub fn prune_inactive_profiles(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        assert!(
+            account_ids.len() <= MAX_PROFILE_BATCH,
+            "At most {} accounts per call",
+            MAX_PROFILE_BATCH
+        );
+
+        let cutoff = env::block_timestamp()
+            .saturating_sub(self.prune_after_seconds.saturating_mul(1_000_000_000));
+        for account_id in &account_ids {
+            let profile = self
+                .profiles
+                .get(account_id)
+                .unwrap_or_else(|| env::panic_str(&format!("No profile for {}", account_id)));
+            let last_active = self.last_active.get(account_id).unwrap_or(0);
+            assert!(
+                profile.registered_at.max(last_active) < cutoff,
+                "Profile {} was used too recently to prune",
+                account_id
+            );
+        }
+
+        for account_id in account_ids {
+            let (_, refund) = self.remove_profile(&account_id);
+            self.emit_event(
+                "profile_pruned",
+                serde_json::json!({
+                    "account_id": account_id.to_string(),
+                    "refund": refund.as_yoctonear().to_string(),
+                }),
+            );
+            if !refund.is_zero() {
+                Promise::new(account_id).transfer(refund);
+            }
+        }
+    }
+
+    /// Idle time after which `prune_inactive_profiles` may remove a profile
+    pub fn set_prune_threshold(&mut self, seconds: u64) {
+        self.assert_owner();
+        self.prune_after_seconds = seconds;
+    }
+
+    /// Overrides the gas reserved for payment transfers; `None` restores
+    /// `TRANSFER_GAS`
+    pub fn set_transfer_gas(&mut self, gas: Option<Gas>) {
+        self.assert_owner();
+        self.transfer_gas = gas;
+    }
+
+    /// Checkpoint for indexers, meant to be called once a day by a cron account
+    pub fn emit_daily_stats(&mut self) {
+        self.assert_owner();
+        self.emit_event(
+            "stats_snapshot",
+            serde_json::json!({
+                "profile_count": self.profile_count,
+                "message_count": self.message_count,
+                "timestamp": env::block_timestamp(),
+            }),
+        );
+    }
+
+    /// Minimum kept by a first `register_key` even if its storage costs less
+    pub fn set_registration_deposit(&mut self, amount: NearToken) {
+        self.assert_owner();
+        self.registration_deposit = amount;
+    }
+
+    pub fn set_group_deposit(&mut self, amount: NearToken) {
+        self.assert_owner();
+        self.group_deposit = amount;
+    }
+
+    /// Advisory only: how long relays should keep message ciphertext. 0 means
+    /// no recommendation.
+    pub fn set_default_ttl(&mut self, ttl_seconds: u64) {
+        self.assert_owner();
+        self.default_message_ttl_seconds = ttl_seconds;
+    }
+
+    /// Namespace for events emitted from now on; indexers must follow it
+    pub fn set_event_standard(&mut self, event_standard: String) {
+        self.assert_owner();
+        assert!(
+            !event_standard.is_empty() && event_standard.len() <= MAX_EVENT_STANDARD_LEN,
+            "Event standard must be 1-{} bytes",
+            MAX_EVENT_STANDARD_LEN
+        );
+        self.event_standard = event_standard;
+    }
+
+    // ========================================================================
+    // View Methods
+    // ========================================================================
+
+    pub fn get_profile(&self, account_id: AccountId) -> Option<MessagingProfile> {
+        self.profiles.get(&account_id)
+    }
+
+    pub fn has_profile(&self, account_id: AccountId) -> bool {
+        self.profiles.get(&account_id).is_some()
+    }
+
+    /// False for deactivated or unregistered accounts
+    pub fn is_active(&self, account_id: AccountId) -> bool {
+        self.profiles
+            .get(&account_id)
+            .is_some_and(|profile| profile.active)
+    }
+
+    /// `owner`'s profile plus how `owner` relates to `viewer`, so a profile
+    /// screen needs one call instead of three.
+    pub fn get_profile_with_relationship(
+        &self,
+        owner: AccountId,
+        viewer: AccountId,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "profile": self.profiles.get(&owner),
+            "blocked_by_owner": self.blocked.contains(&(owner.clone(), viewer.clone())),
+            "is_contact_of_owner": self.contacts.contains(&(owner, viewer)),
+        })
+    }
+
+    /// Resolves several profiles in one call; results follow input order.
+    pub fn get_profiles(&self, account_ids: Vec<AccountId>) -> Vec<Option<MessagingProfile>> {
+        assert!(
+            account_ids.len() <= MAX_PROFILE_BATCH,
+            "At most {} accounts per call",
+            MAX_PROFILE_BATCH
+        );
+        account_ids.iter().map(|id| self.profiles.get(id)).collect()
+    }
+
+    /// Like `get_profiles` but only checks for the key, skipping deserialization
+    pub fn profiles_exist(&self, account_ids: Vec<AccountId>) -> Vec<bool> {
+        assert!(
+            account_ids.len() <= MAX_EXISTENCE_BATCH,
+            "At most {} accounts per call",
+            MAX_EXISTENCE_BATCH
+        );
+        account_ids.iter().map(|id| self.profiles.contains_key(id)).collect()
+    }
+
+    /// Worst-case storage cost of registering a profile with `display_name`
+    /// and a signing key, so clients can attach the right deposit to
+    /// `register_key`.
+    pub fn storage_cost_estimate(&self, display_name: Option<String>) -> U128 {
+        let name_len = display_name.as_ref().map(|name| name.len() as u64);
+        let profile = MessagingProfile {
+            x25519_pubkey: BASE64.encode([0u8; 32]),
+            display_name,
+            ed25519_pubkey: Some(BASE64.encode([0u8; 32])),
+            ..Default::default()
+        };
+        let profile_len = borsh::to_vec(&profile).unwrap().len() as u64;
+
+        // Storage prefix byte plus a Borsh-encoded (length-prefixed) account id
+        let account_key_len = 1 + 4 + MAX_ACCOUNT_ID_LEN;
+        let profile_bytes = STORAGE_RECORD_OVERHEAD + account_key_len + profile_len;
+        // `profile_index` adds an element-index entry and a vector slot
+        let index_bytes = 2 * (STORAGE_RECORD_OVERHEAD + account_key_len + 1 + 8);
+        // `display_names` maps the name back to the (length-prefixed) account id
+        let name_bytes = name_len.map_or(0, |len| {
+            STORAGE_RECORD_OVERHEAD + 1 + 4 + len + 4 + MAX_ACCOUNT_ID_LEN
+        });
+        // The four per-account `u64` counters and the `NearToken` tally
+        // from `allocate_counters`
+        let counter_bytes = 4 * (STORAGE_RECORD_OVERHEAD + account_key_len + 8)
+            + STORAGE_RECORD_OVERHEAD
+            + account_key_len
+            + 16;
+
+        let total_bytes = profile_bytes + index_bytes + name_bytes + counter_bytes;
+        let cost = env::storage_byte_cost().as_yoctonear() * u128::from(total_bytes);
+        U128(cost.max(self.registration_deposit.as_yoctonear()))
+    }
+
+    /// Keys this account has rotated away from, oldest first
+    pub fn get_key_history(&self, account_id: AccountId) -> Vec<KeyHistoryEntry> {
+        self.key_history.get(&account_id).unwrap_or_default()
+    }
+
+    pub fn resolve_name(&self, name: String) -> Option<AccountId> {
+        self.display_names.get(&display_name_key(&name))
+    }
+
+    pub fn get_device_keys(&self, account_id: AccountId) -> Vec<DeviceKey> {
+        self.profiles
+            .get(&account_id)
+            .map_or_else(Vec::new, |profile| profile.devices)
+    }
+
+    /// Pages through registered profiles; order is stable except that
+    /// unregistering moves the last entry into the vacated slot.
+    pub fn list_profiles(&self, from_index: u64, limit: u64) -> Vec<(AccountId, MessagingProfile)> {
+        let index = self.profile_index.as_vector();
+        let end = from_index.saturating_add(limit.min(MAX_PAGE_LIMIT)).min(index.len());
+        (from_index..end)
+            .filter_map(|i| {
+                let account_id = index.get(i)?;
+                let profile = self.profiles.get(&account_id)?;
+                Some((account_id, profile))
+            })
+            .collect()
+    }
+
+    /// Profiles registered strictly after `timestamp`. The index isn't sorted
+    /// by time, so `from_index`/`limit` page over index slots as in
+    /// `list_profiles` and a page may hold fewer matches than `limit`.
+    pub fn profiles_registered_after(
+        &self,
+        timestamp: u64,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(AccountId, MessagingProfile)> {
+        self.list_profiles(from_index, limit)
+            .into_iter()
+            .filter(|(_, profile)| profile.registered_at > timestamp)
+            .collect()
+    }
+
+    pub fn is_group_muted(&self, group_id: String, member: AccountId) -> bool {
+        self.group_mutes.get(&(group_id, member)).unwrap_or(false)
+    }
+
+    /// 0 until `member` sets a cursor in `group_id`
+    pub fn get_group_read_cursor(&self, group_id: String, member: AccountId) -> u64 {
+        self.group_read_cursors.get(&(group_id, member)).unwrap_or(0)
+    }
+
+    /// Members of `group_id` in join order; empty for unknown groups
+    pub fn get_group_members(
+        &self,
+        group_id: String,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<AccountId> {
+        self.groups
+            .get(&group_id)
+            .map(|group| {
+                group
+                    .members
+                    .into_iter()
+                    .skip(from_index as usize)
+                    .take(limit.min(MAX_PAGE_LIMIT) as usize)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn get_groups_by_creator(
+        &self,
+        creator: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<GroupChat> {
+        let created = self.groups_by_creator.get(&creator).unwrap_or_default();
+        created
+            .iter()
+            .skip(from_index as usize)
+            .take(limit.min(MAX_PAGE_LIMIT) as usize)
+            .filter_map(|group_id| self.groups.get(group_id))
+            .collect()
+    }
+
+    /// Messages sent while registered; kept after `unregister`
+    pub fn get_sent_count(&self, account_id: AccountId) -> u64 {
+        self.sent_counts.get(&account_id).unwrap_or(0)
+    }
+
+    /// Direct messages `from` has sent `to`; the reverse direction is
+    /// counted separately. Counting starts once a registered `from` pays for
+    /// the pair, from a `send_message` deposit or a `storage_deposit` balance.
+    pub fn conversation_count(&self, from: AccountId, to: AccountId) -> u64 {
+        self.conversation_counts.get(&(from, to)).unwrap_or(0)
+    }
+
+    /// Highest message id assigned so far, for resuming event sync
+    pub fn last_message_id(&self) -> u64 {
+        self.message_count
+    }
+
+    /// Id of the latest direct message sent to `account_id`, or 0
+    pub fn last_message_id_for_recipient(&self, account_id: AccountId) -> u64 {
+        self.last_message_ids.get(&account_id).unwrap_or(0)
+    }
+
+    /// Sequence number of the latest direct message sent to `account_id`
+    pub fn get_inbox_seq(&self, account_id: AccountId) -> u64 {
+        self.inbox_seqs.get(&account_id).unwrap_or(0)
+    }
+
+    pub fn is_blocked(&self, owner: AccountId, who: AccountId) -> bool {
+        self.blocked.contains(&(owner, who))
+    }
+
+    pub fn is_contact(&self, owner: AccountId, who: AccountId) -> bool {
+        self.contacts.contains(&(owner, who))
+    }
+
+    /// Whether `account_id` may register while `allowlist_enabled` is set
+    pub fn is_allowlisted(&self, account_id: AccountId) -> bool {
+        self.allowlist.contains(&account_id)
+    }
+
+    pub fn is_ft_token(&self, token_contract: AccountId) -> bool {
+        self.ft_tokens.contains(&token_contract)
+    }
+
+    /// Whether a message from `from` to `to` would pass the recipient's
+    /// gating rules right now
+    pub fn can_send(&self, from: AccountId, to: AccountId) -> bool {
+        self.check_can_send(&from, &to).is_ok()
+    }
+
+    /// Gas to attach to `send_group_message` for this group
+    pub fn group_message_gas_estimate(&self, group_id: String) -> u64 {
+        let group = self
+            .groups
+            .get(&group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        group_message_gas(&group).as_gas()
+    }
+
+    pub fn get_inbound_fee(&self, account_id: AccountId) -> Option<U128> {
+        self.profiles
+            .get(&account_id)
+            .and_then(|profile| profile.inbound_fee)
+            .map(|fee| U128(fee.as_yoctonear()))
+    }
+
+    pub fn get_group(&self, group_id: String) -> Option<GroupChat> {
+        self.groups.get(&group_id)
+    }
+
+    pub fn get_banned_substrings(&self) -> Vec<String> {
+        self.banned_substrings.to_vec()
+    }
+
+    pub fn get_channel(&self, channel_id: String) -> Option<Channel> {
+        self.channels.get(&channel_id)
+    }
+
+    pub fn is_subscribed(&self, channel_id: String, account_id: AccountId) -> bool {
+        self.channel_subscribers.contains(&(channel_id, account_id))
+    }
+
+    /// Reaction -> count for a message, `{}` if it has none
+    pub fn get_reactions(&self, message_id: u64) -> serde_json::Value {
+        serde_json::json!(self.reaction_counts.get(&message_id).unwrap_or_default())
+    }
+
+    pub fn get_scheduled_message(&self, schedule_id: u64) -> Option<PendingMessage> {
+        self.pending_messages.get(&schedule_id)
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner.clone()
+    }
+
+    pub fn is_owner(&self, account_id: AccountId) -> bool {
+        account_id == self.owner
+    }
+
+    /// Lets clients enable features by contract build
+    pub fn version(&self) -> String {
+        CONTRACT_VERSION.to_string()
+    }
+
+    /// Lets indexers check compatibility before parsing events.
+    pub fn event_schema_version(&self) -> String {
+        EVENT_VERSION.to_string()
+    }
+
+    pub fn get_group_stats(&self, group_id: String) -> Option<serde_json::Value> {
+        self.groups.get(&group_id).map(|group| {
+            serde_json::json!({
+                "member_count": group.members.len(),
+                "message_count": group.message_count,
+                "created_at": group.created_at,
+            })
+        })
+    }
+
+    /// What `withdraw` can currently send: the account balance minus the
+    /// storage staking reserve and NEAR held for users
+    pub fn available_balance(&self) -> U128 {
+        U128(self.withdrawable_balance().as_yoctonear())
+    }
+
+    pub fn get_stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "profile_count": self.profile_count,
+            "message_count": self.message_count,
+            "dm_count": self.dm_count,
+            "payment_message_count": self.payment_message_count,
+            "group_message_count": self.group_message_count,
+            "owner": self.owner.to_string(),
+            "paused": self.paused,
+            "require_key_proof": self.require_key_proof,
+            "strict_reply_validation": self.strict_reply_validation,
+            "strict_key_version": self.strict_key_version,
+            "allow_self_message": self.allow_self_message,
+            "allowlist_enabled": self.allowlist_enabled,
+            "registration_deposit": U128(self.registration_deposit.as_yoctonear()),
+            "group_deposit": U128(self.group_deposit.as_yoctonear()),
+            "default_message_ttl_seconds": self.default_message_ttl_seconds,
+            "total_value_transferred": U128(self.total_value_transferred.as_yoctonear()),
+            "event_standard": self.event_standard,
+            "min_display_name_len": self.min_display_name_len,
+        })
+    }
+
+    /// NEAR `account_id` has received through payment messages
+    pub fn get_value_received(&self, account_id: AccountId) -> U128 {
+        U128(
+            self.value_received
+                .get(&account_id)
+                .unwrap_or(NearToken::from_yoctonear(0))
+                .as_yoctonear(),
+        )
+    }
+}
+
+impl WhisperContract {
+    fn empty(owner: AccountId) -> Self {
+        Self {
+            profiles: LookupMap::new(StorageKey::Profiles),
+            groups: LookupMap::new(StorageKey::Groups),
+            profile_index: UnorderedSet::new(StorageKey::ProfileIndex),
+            sent_counts: LookupMap::new(StorageKey::SentCounts),
+            blocked: LookupSet::new(StorageKey::BlockList),
+            display_names: LookupMap::new(StorageKey::DisplayNames),
+            contacts: LookupSet::new(StorageKey::Contacts),
+            escrows: LookupMap::new(StorageKey::Escrows),
+            storage_balances: LookupMap::new(StorageKey::StorageBalances),
+            key_history: LookupMap::new(StorageKey::KeyHistory),
+            allowlist: LookupSet::new(StorageKey::Allowlist),
+            groups_by_creator: LookupMap::new(StorageKey::GroupsByCreator),
+            inbox_seqs: LookupMap::new(StorageKey::InboxSeqs),
+            last_message_ids: LookupMap::new(StorageKey::LastMessageIds),
+            group_invites: LookupMap::new(StorageKey::GroupInvites),
+            value_received: LookupMap::new(StorageKey::ValueReceived),
+            last_active: LookupMap::new(StorageKey::LastActive),
+            group_read_cursors: LookupMap::new(StorageKey::GroupReadCursors),
+            banned_substrings: UnorderedSet::new(StorageKey::BannedSubstrings),
+            group_mutes: LookupMap::new(StorageKey::GroupMutes),
+            conversation_counts: LookupMap::new(StorageKey::ConversationCounts),
+            contact_lists: LookupMap::new(StorageKey::ContactLists),
+            reaction_counts: LookupMap::new(StorageKey::ReactionCounts),
+            inbound_windows: LookupMap::new(StorageKey::InboundWindows),
+            group_rate_windows: LookupMap::new(StorageKey::GroupRateWindows),
+            ft_tokens: LookupSet::new(StorageKey::FtTokens),
+            channels: LookupMap::new(StorageKey::Channels),
+            channel_subscribers: LookupSet::new(StorageKey::ChannelSubscribers),
+            pending_messages: LookupMap::new(StorageKey::PendingMessages),
+            scheduled_count: 0,
+            profile_count: 0,
+            message_count: 0,
+            dm_count: 0,
+            payment_message_count: 0,
+            group_message_count: 0,
+            invite_count: 0,
+            owner,
+            paused: false,
+            require_key_proof: false,
+            strict_reply_validation: false,
+            strict_key_version: false,
+            allow_self_message: false,
+            allowlist_enabled: false,
+            default_message_ttl_seconds: 0,
+            registration_deposit: NearToken::from_yoctonear(0),
+            group_deposit: DEFAULT_GROUP_DEPOSIT,
+            held_balance: NearToken::from_yoctonear(0),
+            total_value_transferred: NearToken::from_yoctonear(0),
+            event_standard: DEFAULT_EVENT_STANDARD.to_string(),
+            prune_after_seconds: DEFAULT_PRUNE_AFTER_SECONDS,
+            min_display_name_len: DEFAULT_MIN_DISPLAY_NAME_LEN,
+            transfer_gas: None,
+        }
+    }
+
+    /// Transfers a payment to `to`, first making sure the call has
+    /// `TRANSFER_GAS` (or the owner's override) left to schedule it
+    fn payment_transfer(&self, to: AccountId, amount: NearToken) -> Promise {
+        let reserve = self.transfer_gas.unwrap_or(TRANSFER_GAS);
+        assert!(
+            env::prepaid_gas().saturating_sub(env::used_gas()) >= reserve,
+            "Not enough gas left for the payment transfer"
+        );
+        Promise::new(to).transfer(amount)
+    }
+
+    fn withdrawable_balance(&self) -> NearToken {
+        let storage_reserve =
+            env::storage_byte_cost().saturating_mul(u128::from(env::storage_usage()));
+        env::account_balance()
+            .saturating_sub(storage_reserve)
+            .saturating_sub(self.held_balance)
+    }
+
+    fn emit_event(&self, event: &str, data: serde_json::Value) {
+        let ev = WhisperEvent {
+            standard: &self.event_standard,
+            version: EVENT_VERSION,
+            event,
+            data,
+        };
+        let json = serde_json::to_string(&ev).unwrap();
+        log!("EVENT_JSON:{}", json);
+    }
+
+    /// Applies `update` to the caller's profile and charges any storage it
+    /// adds, like `update_display_name` does
+    fn update_own_profile(&mut self, update: impl FnOnce(&mut MessagingProfile)) {
+        let account_id = env::predecessor_account_id();
+        let mut profile = self
+            .profiles
+            .get(&account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        let storage_before = env::storage_usage();
+        update(&mut profile);
+        self.profiles.insert(&account_id, &profile);
+
+        let storage_cost =
+            self.charge_storage(&account_id, storage_before, NearToken::from_yoctonear(0));
+        profile.storage_paid = profile.storage_paid.saturating_add(storage_cost);
+        self.profiles.insert(&account_id, &profile);
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner,
+            "Only the owner can do this"
+        );
+    }
+
+    /// Moves `account_id`'s claim from `previous` to `next`, panicking if
+    /// `next` already belongs to someone else.
+    fn update_display_name_claim(
+        &mut self,
+        account_id: &AccountId,
+        previous: Option<&str>,
+        next: Option<&str>,
+    ) {
+        let previous_key = previous.map(display_name_key);
+        let next_key = next.map(display_name_key);
+        if previous_key == next_key {
+            return;
+        }
+
+        if let Some(name) = next {
+            validate_display_name(name);
+            self.assert_display_name_allowed(name);
+            let key = display_name_key(name);
+            assert!(
+                self.display_names.get(&key).is_none(),
+                "Display name is already taken"
+            );
+            self.display_names.insert(&key, account_id);
+        }
+        // A migrated profile may still show a name someone else has claimed
+        // since; only release a claim this account actually holds
+        if let Some(key) = previous_key {
+            if self.display_names.get(&key).as_ref() == Some(account_id) {
+                self.display_names.remove(&key);
+            }
+        }
+    }
+
+    /// Adds a migrated profile to `profile_index`, credits its legacy
+    /// deposit and claims its display name, unless someone registered that
+    /// name since the upgrade
+    fn backfill_profile(&mut self, account_id: &AccountId) {
+        let Some(mut profile) = self.profiles.get(account_id) else {
+            return;
+        };
+        if !self.profile_index.insert(account_id) {
+            return;
+        }
+        profile.storage_paid = profile.storage_paid.saturating_add(LEGACY_REGISTRATION_DEPOSIT);
+        self.profiles.insert(account_id, &profile);
+        if let Some(name) = &profile.display_name {
+            let key = display_name_key(name);
+            match self.display_names.get(&key) {
+                None => {
+                    self.display_names.insert(&key, account_id);
+                }
+                Some(holder) if holder != *account_id => {
+                    log!("Display name of {} is already claimed by {}", account_id, holder);
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Owner-configured rules on top of `validate_display_name`. Names that
+    /// were claimed before a rule changed keep working.
+    fn assert_display_name_allowed(&self, name: &str) {
+        assert!(
+            name.chars().count() >= usize::from(self.min_display_name_len),
+            "Display name must be at least {} characters",
+            self.min_display_name_len
+        );
+        let lowered = name.to_lowercase();
+        assert!(
+            !self
+                .banned_substrings
+                .iter()
+                .any(|banned| lowered.contains(&banned)),
+            "Display name contains a banned word"
+        );
+    }
+
+    fn record_retired_key(&mut self, account_id: &AccountId, previous: &MessagingProfile) {
+        let mut history = self.key_history.get(account_id).unwrap_or_default();
+        history.push(KeyHistoryEntry {
+            x25519_pubkey: previous.x25519_pubkey.clone(),
+            key_version: previous.key_version,
+            retired_at: env::block_timestamp(),
+        });
+        if history.len() > MAX_KEY_HISTORY {
+            history.remove(0);
+        }
+        self.key_history.insert(account_id, &history);
+    }
+
+    /// Charges the attached deposit for storage added since `storage_before`
+    /// and refunds whatever is left over. Returns the amount kept.
+    fn charge_storage(
+        &mut self,
+        account_id: &AccountId,
+        storage_before: u64,
+        minimum: NearToken,
+    ) -> NearToken {
+        let storage_used = env::storage_usage().saturating_sub(storage_before);
+        let required = env::storage_byte_cost()
+            .saturating_mul(u128::from(storage_used))
+            .max(minimum);
+        let deposit = env::attached_deposit();
+
+        let shortfall = required.saturating_sub(deposit);
+        if !shortfall.is_zero() {
+            let mut balance = self
+                .storage_balances
+                .get(account_id)
+                .filter(|balance| balance.available.0 >= shortfall.as_yoctonear())
+                .unwrap_or_else(|| {
+                    env::panic_str(&format!(
+                        "Insufficient storage deposit: attached {} yoctoNEAR, required {}",
+                        deposit.as_yoctonear(),
+                        required.as_yoctonear()
+                    ))
+                });
+            balance.available = U128(balance.available.0 - shortfall.as_yoctonear());
+            self.storage_balances.insert(account_id, &balance);
+            self.held_balance = self.held_balance.saturating_sub(shortfall);
+        }
+
+        let refund = deposit.saturating_sub(required);
+        if !refund.is_zero() {
+            Promise::new(account_id.clone()).transfer(refund);
+            self.emit_event(
+                "storage_refunded",
+                serde_json::json!({
+                    "account_id": account_id.to_string(),
+                    "amount": refund.as_yoctonear().to_string(),
+                }),
+            );
+        }
+        required
+    }
+
+    /// Counterpart of `charge_storage` for removals: refunds the storage
+    /// freed since `storage_before` to `account_id`
+    fn refund_storage(&mut self, account_id: &AccountId, storage_before: u64) -> NearToken {
+        let storage_freed = storage_before.saturating_sub(env::storage_usage());
+        let refund = env::storage_byte_cost().saturating_mul(u128::from(storage_freed));
+        if !refund.is_zero() {
+            Promise::new(account_id.clone()).transfer(refund);
+            self.emit_event(
+                "storage_refunded",
+                serde_json::json!({
+                    "account_id": account_id.to_string(),
+                    "amount": refund.as_yoctonear().to_string(),
+                }),
+            );
+        }
+        refund
+    }
+
+    fn assert_not_paused(&self) {
+        assert!(!self.paused, "Contract is paused");
+    }
+
+    fn is_stale_key_version(&self, to: &AccountId, recipient_key_version: u32) -> bool {
+        if !self.strict_key_version {
+            return false;
+        }
+        // Callers have already checked the recipient is registered
+        let current = self.profiles.get(to).map_or(0, |p| p.key_version);
+        recipient_key_version < current
+    }
+
+    fn validate_recipient_key_version(&self, to: &AccountId, recipient_key_version: u32) {
+        assert!(
+            !self.is_stale_key_version(to, recipient_key_version),
+            "Stale recipient key version"
+        );
+    }
+
+    fn validate_reply_to(&self, reply_to: &Option<String>) {
+        let Some(reply_to) = reply_to else { return };
+        if !self.strict_reply_validation {
+            return;
+        }
+
+        let parent: u64 = reply_to
+            .parse()
+            .unwrap_or_else(|_| env::panic_str("reply_to must be a message id"));
+        assert!(
+            parent <= self.message_count,
+            "reply_to references a future message"
+        );
+    }
+
+    /// Every rule deciding whether `from` may message `to`. Both the send
+    /// methods and the `can_send` view go through here; new gating rules
+    /// belong in `check_can_reach` unless they only apply to messages.
+    fn check_can_send(&self, from: &AccountId, to: &AccountId) -> Result<(), SendRejection> {
+        let recipient = self.check_can_reach(from, to)?;
+        if let Some(limit) = recipient.inbound_rate_limit {
+            if next_rate_window(limit, self.inbound_windows.get(to)).is_none() {
+                return Err(SendRejection::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    /// `check_can_send` without the inbound rate limit, which only counts
+    /// messages. Reactions and typing indicators go through here. Returns
+    /// the recipient's profile.
+    fn check_can_reach(
+        &self,
+        from: &AccountId,
+        to: &AccountId,
+    ) -> Result<MessagingProfile, SendRejection> {
+        if from == to && !self.allow_self_message {
+            return Err(SendRejection::SelfMessage);
+        }
+        let recipient = self
+            .profiles
+            .get(to)
+            .ok_or(SendRejection::RecipientNotRegistered)?;
+        if recipient.revoked {
+            return Err(SendRejection::RecipientRevoked);
+        }
+        if !recipient.active {
+            return Err(SendRejection::RecipientInactive);
+        }
+        if self.blocked.contains(&(to.clone(), from.clone())) {
+            return Err(SendRejection::Blocked);
+        }
+        if recipient.contacts_only && !self.contacts.contains(&(to.clone(), from.clone())) {
+            return Err(SendRejection::ContactsOnly);
+        }
+        Ok(recipient)
+    }
+
+    fn assert_can_send(&self, from: &AccountId, to: &AccountId) {
+        if let Err(rejection) = self.check_can_send(from, to) {
+            env::panic_str(rejection.message());
+        }
+    }
+
+    fn assert_can_reach(&self, from: &AccountId, to: &AccountId) {
+        if let Err(rejection) = self.check_can_reach(from, to) {
+            env::panic_str(rejection.message());
+        }
+    }
+
+    /// The fee `from` owes `to` per message; contacts are exempt
+    fn inbound_fee_for(&self, from: &AccountId, to: &AccountId) -> Option<NearToken> {
+        if self.contacts.contains(&(to.clone(), from.clone())) {
+            return None;
+        }
+        self.profiles.get(to).and_then(|profile| profile.inbound_fee)
+    }
+
+    /// Forwards the recipient's inbound fee out of the attached deposit.
+    /// Returns the fee charged, if any; the caller refunds the rest.
+    fn collect_inbound_fee(&self, from: &AccountId, to: &AccountId) -> Option<NearToken> {
+        let fee = self.inbound_fee_for(from, to);
+        if let Some(fee) = fee {
+            assert!(
+                env::attached_deposit() >= fee,
+                "Attach at least {} yoctoNEAR for the recipient's inbound fee",
+                fee.as_yoctonear()
+            );
+            Promise::new(to.clone()).transfer(fee);
+        }
+        fee
+    }
+
+    /// Counts one message against `to`'s inbound rate limit, starting a
+    /// fresh window once the current one has passed. `check_can_send`
+    /// already refused the message if the window is full, and
+    /// `set_inbound_rate_limit` allocated it, so this only overwrites it.
+    fn consume_inbound_quota(&mut self, to: &AccountId) {
+        let Some(limit) = self.profiles.get(to).and_then(|profile| profile.inbound_rate_limit)
+        else {
+            return;
+        };
+        let window = next_rate_window(limit, self.inbound_windows.get(to))
+            .unwrap_or_else(|| env::panic_str(SendRejection::RateLimited.message()));
+        self.inbound_windows.insert(to, &window);
+    }
+
+    fn consume_group_quota(&mut self, group: &GroupChat, member: &AccountId) {
+        let Some(limit) = group.rate_limit else {
+            return;
+        };
+        let key = (group.group_id.clone(), member.clone());
+        let window = next_rate_window(limit, self.group_rate_windows.get(&key))
+            .unwrap_or_else(|| env::panic_str("Group rate limit exceeded"));
+        self.group_rate_windows.insert(&key, &window);
+    }
+
+    /// Only call after `assert_can_send`, which guarantees `to` has a profile
+    fn assert_accepts_payments(&self, to: &AccountId) {
+        assert!(
+            self.profiles.get(to).is_some_and(|profile| profile.accepts_payments),
+            "Recipient does not accept payments"
+        );
+    }
+
+    /// Send checks for a scheduled message, run when it is scheduled and
+    /// again when it is delivered. Nobody is there to pay an inbound fee at
+    /// delivery, so recipients that charge one can't be scheduled to.
+    fn assert_can_schedule(&self, from: &AccountId, to: &AccountId, recipient_key_version: u32) {
+        self.assert_can_send(from, to);
+        self.validate_recipient_key_version(to, recipient_key_version);
+        assert!(
+            self.inbound_fee_for(from, to).is_none(),
+            "Recipient charges an inbound fee; use send_message"
+        );
+    }
+
+    /// Payment messages satisfy the inbound fee with the payment itself
+    fn assert_payment_covers_fee(&self, from: &AccountId, to: &AccountId, amount: NearToken) {
+        if let Some(fee) = self.inbound_fee_for(from, to) {
+            assert!(amount >= fee, "Payment is below the recipient's inbound fee");
+        }
+    }
+
+    /// Assigns the next message id and emits a `message` event. `data` holds
+    /// the method-specific fields; id, sender, recipient, timestamp, the
+    /// recipient's inbox sequence and, unless the caller set one, the
+    /// priority are filled in here.
+    fn emit_message(
+        &mut self,
+        from: &AccountId,
+        to: &AccountId,
+        mut data: serde_json::Value,
+    ) -> u64 {
+        // Every direct send lands here, so none can skip the recipient's limit
+        self.consume_inbound_quota(to);
+        self.message_count += 1;
+        let message_id = self.message_count;
+        let is_payment = data.get("payment").is_some();
+        if is_payment {
+            self.payment_message_count += 1;
+        } else {
+            self.dm_count += 1;
+        }
+        self.increment_sent_count(from);
+        let recipient_seq = self.inbox_seqs.get(to).unwrap_or(0) + 1;
+        self.inbox_seqs.insert(to, &recipient_seq);
+        self.last_message_ids.insert(to, &message_id);
+        self.count_conversation(from, to);
+
+        data["id"] = message_id.into();
+        data["from"] = from.to_string().into();
+        data["to"] = to.to_string().into();
+        data["timestamp"] = env::block_timestamp().into();
+        data["ttl"] = self.default_message_ttl_seconds.into();
+        data["recipient_seq"] = recipient_seq.into();
+        if data["priority"].is_null() {
+            data["priority"] = if is_payment { PRIORITY_HIGH } else { PRIORITY_LOW }.into();
+        }
+        self.emit_event("message", data);
+
+        message_id
+    }
+
+    fn record_value_transferred(&mut self, to: &AccountId, amount: NearToken) {
+        self.total_value_transferred = self.total_value_transferred.saturating_add(amount);
+        // Registration allocated the tally, so a payment never creates one;
+        // recipients who have since unregistered aren't tracked
+        if let Some(received) = self.value_received.get(to) {
+            self.value_received.insert(to, &received.saturating_add(amount));
+        }
+    }
+
+    /// Per-sender counters are keyed independently of `profiles`, so they
+    /// survive `unregister`. Only registered senders are counted, since
+    /// their registration paid for the entries.
+    fn increment_sent_count(&mut self, from: &AccountId) {
+        if !self.profiles.contains_key(from) {
+            return;
+        }
+        let count = self.sent_counts.get(from).unwrap_or(0);
+        self.sent_counts.insert(from, &(count + 1));
+        self.last_active.insert(from, &env::block_timestamp());
+    }
+
+    /// Creates the per-account counter entries up front, so a new profile's
+    /// measured storage pays for them instead of the contract
+    fn allocate_counters(&mut self, account_id: &AccountId) {
+        if self.sent_counts.get(account_id).is_none() {
+            self.sent_counts.insert(account_id, &0);
+        }
+        self.last_active.insert(account_id, &env::block_timestamp());
+        if self.inbox_seqs.get(account_id).is_none() {
+            self.inbox_seqs.insert(account_id, &0);
+        }
+        if self.last_message_ids.get(account_id).is_none() {
+            self.last_message_ids.insert(account_id, &0);
+        }
+        if self.value_received.get(account_id).is_none() {
+            self.value_received.insert(account_id, &NearToken::from_yoctonear(0));
+        }
+    }
+
+    /// Storage of `account_id`'s `sent_counts` entry, which outlives the profile
+    fn sent_count_storage_cost(account_id: &AccountId) -> NearToken {
+        let bytes = STORAGE_RECORD_OVERHEAD + 1 + 4 + account_id.as_str().len() as u64 + 8;
+        env::storage_byte_cost().saturating_mul(u128::from(bytes))
+    }
+
+    /// Counts a direct message for the `(from, to)` pair. A pair's first
+    /// message is only counted here if `from`'s NEP-145 balance covers the
+    /// entry; `send_message` can still pay for it with `fund_conversation`.
+    fn count_conversation(&mut self, from: &AccountId, to: &AccountId) {
+        let pair = (from.clone(), to.clone());
+        if let Some(count) = self.conversation_counts.get(&pair) {
+            self.conversation_counts.insert(&pair, &(count + 1));
+            return;
+        }
+        if !self.profiles.contains_key(from) {
+            return;
+        }
+        let storage_before = env::storage_usage();
+        self.conversation_counts.insert(&pair, &1);
+        if !self.draw_storage_balance(from, storage_before) {
+            self.conversation_counts.remove(&pair);
+        }
+    }
+
+    /// Starts counting `(from, to)` out of `deposit` when `count_conversation`
+    /// couldn't draw the pair from `from`'s NEP-145 balance. Returns what is
+    /// left of `deposit`; if it falls short, the pair stays uncounted.
+    fn fund_conversation(
+        &mut self,
+        from: &AccountId,
+        to: &AccountId,
+        deposit: NearToken,
+    ) -> NearToken {
+        let pair = (from.clone(), to.clone());
+        if !self.profiles.contains_key(from) || self.conversation_counts.contains_key(&pair) {
+            return deposit;
+        }
+        let storage_before = env::storage_usage();
+        self.conversation_counts.insert(&pair, &1);
+        let storage_used = env::storage_usage().saturating_sub(storage_before);
+        let cost = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        if cost > deposit {
+            self.conversation_counts.remove(&pair);
+            return deposit;
+        }
+        deposit.saturating_sub(cost)
+    }
+
+    /// Pays for storage used since `storage_before` out of `account_id`'s
+    /// NEP-145 balance. Returns false, charging nothing, if it falls short.
+    fn draw_storage_balance(&mut self, account_id: &AccountId, storage_before: u64) -> bool {
+        let storage_used = env::storage_usage().saturating_sub(storage_before);
+        let cost = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        let Some(mut balance) = self
+            .storage_balances
+            .get(account_id)
+            .filter(|balance| balance.available.0 >= cost.as_yoctonear())
+        else {
+            return false;
+        };
+        balance.available = U128(balance.available.0 - cost.as_yoctonear());
+        self.storage_balances.insert(account_id, &balance);
+        self.held_balance = self.held_balance.saturating_sub(cost);
+        true
+    }
+
+    /// Deletes `account_id`'s profile and everything keyed to it, returning
+    /// the profile and the part of its storage deposit to refund directly.
+    /// Storage drawn from a NEP-145 balance goes back to that balance instead.
+    fn remove_profile(&mut self, account_id: &AccountId) -> (MessagingProfile, NearToken) {
+        let profile = self
+            .profiles
+            .remove(account_id)
+            .unwrap_or_else(|| env::panic_str("No messaging key registered"));
+        self.profile_count -= 1;
+        self.profile_index.remove(account_id);
+        self.update_display_name_claim(account_id, profile.display_name.as_deref(), None);
+        self.key_history.remove(account_id);
+        self.last_active.remove(account_id);
+        self.inbox_seqs.remove(account_id);
+        self.last_message_ids.remove(account_id);
+        self.value_received.remove(account_id);
+        self.inbound_windows.remove(account_id);
+
+        let mut refund = profile.storage_paid;
+        if self.sent_counts.get(account_id).is_some() {
+            refund = refund.saturating_sub(Self::sent_count_storage_cost(account_id));
+        }
+        if let Some(mut balance) = self.storage_balances.get(account_id) {
+            let locked = balance.total.0 - balance.available.0;
+            let released = locked.min(refund.as_yoctonear());
+            balance.available = U128(balance.available.0 + released);
+            self.storage_balances.insert(account_id, &balance);
+            self.held_balance = self
+                .held_balance
+                .saturating_add(NearToken::from_yoctonear(released));
+            refund = refund.saturating_sub(NearToken::from_yoctonear(released));
+        }
+        (profile, refund)
+    }
+
+    /// Returns false if `account_id` already was a contact of `owner`
+    fn insert_contact(&mut self, owner: &AccountId, account_id: &AccountId) -> bool {
+        if !self.contacts.insert(&(owner.clone(), account_id.clone())) {
+            return false;
+        }
+        let mut list = self.contact_lists.get(owner).unwrap_or_default();
+        list.push(account_id.clone());
+        self.contact_lists.insert(owner, &list);
+        true
+    }
+
+    fn get_group_as_creator(&self, group_id: &String) -> GroupChat {
+        let group = self
+            .groups
+            .get(group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        assert_eq!(
+            env::predecessor_account_id(),
+            group.creator,
+            "Only the group creator can do this"
+        );
+        group
+    }
+
+    /// The creator is always an admin
+    fn get_group_as_admin(&self, group_id: &String) -> GroupChat {
+        let group = self
+            .groups
+            .get(group_id)
+            .unwrap_or_else(|| env::panic_str("Group does not exist"));
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == group.creator || group.admins.contains(&caller),
+            "Only a group admin can do this"
+        );
+        group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::mock::MockAction;
+    use near_sdk::test_utils::{get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    fn get_context(predecessor: &str) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder.predecessor_account_id(predecessor.parse().unwrap());
+        builder.attached_deposit(NearToken::from_millinear(100));
+        builder
+    }
+
+    fn test_nonce() -> String {
+        BASE64.encode([7u8; 24])
+    }
+
+    fn events() -> Vec<serde_json::Value> {
+        get_logs()
+            .iter()
+            .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+            .map(|json| serde_json::from_str(json).unwrap())
+            .collect()
+    }
+
+    fn last_event() -> serde_json::Value {
+        events().pop().expect("no event emitted")
+    }
+
+    #[test]
+    fn test_register_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let pubkey = BASE64.encode([1u8; 32]);
+        contract.register_key(pubkey.clone(), Some("Alice".to_string()), None, None, None, None);
+
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.x25519_pubkey, pubkey);
+        assert_eq!(profile.key_version, 1);
+        assert_eq!(contract.profile_count, 1);
+    }
+
+    #[test]
+    fn test_batch_register() {
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_near(1));
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.batch_register(vec![
+            ("bob.near".parse().unwrap(), BASE64.encode([2u8; 32]), None),
+            ("carol.near".parse().unwrap(), BASE64.encode([3u8; 32]), Some("Carol".to_string())),
+            ("dave.near".parse().unwrap(), BASE64.encode([4u8; 32]), None),
+        ]);
+
+        assert_eq!(contract.profile_count, 3);
+        assert_eq!(
+            contract.resolve_name("carol".to_string()),
+            Some("carol.near".parse().unwrap())
+        );
+        let profile = contract.get_profile("dave.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.key_version, 1);
+        assert!(!profile.storage_paid.is_zero());
+        let registered = events()
+            .iter()
+            .filter(|event| event["event"] == "key_registered")
+            .count();
+        assert_eq!(registered, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "X25519 pubkey must be 32 bytes")]
+    fn test_batch_register_rejects_invalid_key() {
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_near(1));
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.batch_register(vec![
+            ("bob.near".parse().unwrap(), BASE64.encode([2u8; 32]), None),
+            ("carol.near".parse().unwrap(), BASE64.encode([3u8; 16]), None),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account bob.near appears twice in batch")]
+    fn test_batch_register_rejects_duplicate_account() {
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_near(1));
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.batch_register(vec![
+            ("bob.near".parse().unwrap(), BASE64.encode([2u8; 32]), Some("Bob".to_string())),
+            ("carol.near".parse().unwrap(), BASE64.encode([3u8; 32]), None),
+            ("bob.near".parse().unwrap(), BASE64.encode([4u8; 32]), Some("Robert".to_string())),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can do this")]
+    fn test_batch_register_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+
+        testing_env!(get_context("bob.near").build());
+        contract.batch_register(vec![(
+            "bob.near".parse().unwrap(),
+            BASE64.encode([2u8; 32]),
+            None,
+        )]);
+    }
+
+    #[test]
+    fn test_rotate_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let pubkey1 = BASE64.encode([1u8; 32]);
+        contract.register_key(pubkey1, None, None, None, None, None);
+
+        let pubkey2 = BASE64.encode([2u8; 32]);
+        contract.register_key(pubkey2.clone(), None, None, None, None, None);
+
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.x25519_pubkey, pubkey2);
+        assert_eq!(profile.key_version, 2);
+        assert_eq!(contract.profile_count, 1);
+    }
+
+    #[test]
+    fn test_key_history() {
+        let mut context = get_context("alice.near");
+        context.block_timestamp(10);
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.register_key(BASE64.encode([3u8; 32]), None, None, None, None, None);
+
+        let history = contract.get_key_history("alice.near".parse().unwrap());
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].x25519_pubkey, BASE64.encode([1u8; 32]));
+        assert_eq!(history[0].key_version, 1);
+        assert_eq!(history[0].retired_at, 10);
+        assert_eq!(history[1].x25519_pubkey, BASE64.encode([2u8; 32]));
+        assert_eq!(history[1].key_version, 2);
+    }
+
+    #[test]
+    fn test_key_history_is_capped() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        for i in 0..(MAX_KEY_HISTORY as u8 + 2) {
+            contract.register_key(BASE64.encode([i + 1; 32]), None, None, None, None, None);
+        }
+
+        let history = contract.get_key_history("alice.near".parse().unwrap());
+        assert_eq!(history.len(), MAX_KEY_HISTORY);
+        assert_eq!(history[0].key_version, 2);
+    }
+
+    #[test]
+    fn test_registration_and_rotation_events() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let event = last_event();
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(event["event"], "key_registered");
+        assert_eq!(
+            event["data"]["storage_deposit"],
+            profile.storage_paid.as_yoctonear().to_string()
+        );
+        assert!(event["data"].get("previous_key_version").is_none());
+
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        let event = last_event();
+        assert_eq!(event["event"], "key_rotated");
+        assert_eq!(event["data"]["previous_key_version"], 1);
+        assert_eq!(event["data"]["key_version"], 2);
+        assert!(event["data"].get("storage_deposit").is_none());
+    }
+
+    #[test]
+    fn test_device_keys() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.add_device_key("phone".to_string(), BASE64.encode([2u8; 32]));
+        contract.add_device_key("laptop".to_string(), BASE64.encode([3u8; 32]));
+
+        let devices = contract.get_device_keys("alice.near".parse().unwrap());
+        let ids: Vec<&str> = devices.iter().map(|d| d.device_id.as_str()).collect();
+        assert_eq!(ids, vec!["phone", "laptop"]);
+
+        // Rotating the primary key keeps device keys
+        contract.register_key(BASE64.encode([4u8; 32]), None, None, None, None, None);
+        assert_eq!(contract.get_device_keys("alice.near".parse().unwrap()).len(), 2);
+
+        contract.remove_device_key("phone".to_string());
+        let devices = contract.get_device_keys("alice.near".parse().unwrap());
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].device_id, "laptop");
+    }
+
+    #[test]
+    fn test_device_key_charges_measured_storage() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let paid_before = contract.get_profile(alice.clone()).unwrap().storage_paid;
+
+        testing_env!(get_context("alice.near").build());
+        contract.add_device_key("phone".to_string(), BASE64.encode([2u8; 32]));
+        let charged = contract
+            .get_profile(alice)
+            .unwrap()
+            .storage_paid
+            .saturating_sub(paid_before);
+        assert!(!charged.is_zero());
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        let expected = NearToken::from_millinear(100).saturating_sub(charged);
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == expected
+        ));
+    }
+
+    /// XEdDSA-compatible key pair: an Ed25519 key whose public point has sign
+    /// bit 0, returned with its X25519 (Montgomery) public key.
+    fn xeddsa_keypair() -> (ed25519_dalek::SigningKey, [u8; 32]) {
+        (0u8..)
+            .map(|seed| ed25519_dalek::SigningKey::from_bytes(&[seed; 32]))
+            .find(|key| key.verifying_key().as_bytes()[31] & 0x80 == 0)
+            .map(|key| {
+                let x25519 = key.verifying_key().to_montgomery().to_bytes();
+                (key, x25519)
+            })
+            .unwrap()
+    }
+
+    fn key_proof(signing_key: &ed25519_dalek::SigningKey, account: &str, key: &[u8; 32]) -> String {
+        use ed25519_dalek::Signer;
+        let mut message = account.as_bytes().to_vec();
+        message.extend_from_slice(key);
+        BASE64.encode(signing_key.sign(&message).to_bytes())
+    }
+
+    #[test]
+    fn test_register_with_valid_proof() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_require_key_proof(true);
+
+        let (signing_key, x25519) = xeddsa_keypair();
+        let proof = key_proof(&signing_key, "alice.near", &x25519);
+        contract.register_key(BASE64.encode(x25519), None, Some(proof), None, None, None);
+        assert!(contract.has_profile("alice.near".parse().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid key proof")]
+    fn test_register_with_tampered_proof() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_require_key_proof(true);
+
+        let (signing_key, x25519) = xeddsa_keypair();
+        // Proof signed for a different account must not verify
+        let proof = key_proof(&signing_key, "mallory.near", &x25519);
+        contract.register_key(BASE64.encode(x25519), None, Some(proof), None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Key proof required")]
+    fn test_register_without_required_proof() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_require_key_proof(true);
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+    }
+
+    #[test]
+    fn test_allowlisted_registration() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_allowlist_enabled(true);
+        contract.add_to_allowlist("bob.near".parse().unwrap());
+        assert!(contract.is_allowlisted("bob.near".parse().unwrap()));
+
+        testing_env!(get_context("bob.near").build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        assert!(contract.has_profile("bob.near".parse().unwrap()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Account is not on the registration allowlist")]
+    fn test_non_allowlisted_registration() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_allowlist_enabled(true);
+        contract.add_to_allowlist("bob.near".parse().unwrap());
+
+        testing_env!(get_context("carol.near").build());
+        contract.register_key(BASE64.encode([3u8; 32]), None, None, None, None, None);
+    }
+
+    #[test]
+    fn test_resolve_name() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            Some("Alice".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(
+            contract.resolve_name("alice".to_string()),
+            Some("alice.near".parse().unwrap())
+        );
+
+        // Renaming on rotation releases the old name
+        contract.register_key(
+            BASE64.encode([2u8; 32]),
+            Some("Ally".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(contract.resolve_name("Alice".to_string()).is_none());
+        assert_eq!(
+            contract.resolve_name("Ally".to_string()),
+            Some("alice.near".parse().unwrap())
+        );
+
+        drop(contract.unregister());
+        assert!(contract.resolve_name("Ally".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_update_display_name() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            Some("Alice".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.update_display_name(Some("Ally".to_string()));
+
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.display_name.as_deref(), Some("Ally"));
+        assert_eq!(profile.key_version, 1);
+        assert_eq!(profile.x25519_pubkey, BASE64.encode([1u8; 32]));
+        assert!(contract.resolve_name("Alice".to_string()).is_none());
+        assert!(contract.resolve_name("Ally".to_string()).is_some());
+
+        let event = last_event();
+        assert_eq!(event["event"], "display_name_updated");
+        assert_eq!(event["data"]["previous_display_name"], "Alice");
+        assert_eq!(event["data"]["display_name"], "Ally");
+    }
+
+    #[test]
+    fn test_verified_survives_updates() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        testing_env!(get_context("bob.near").build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        contract.set_verified("bob.near".parse().unwrap(), true);
+        let event = last_event();
+        assert_eq!(event["event"], "verification_changed");
+        assert_eq!(event["data"]["verified"], true);
+
+        testing_env!(get_context("bob.near").build());
+        contract.update_display_name(Some("Bob".to_string()));
+        contract.register_key(
+            BASE64.encode([3u8; 32]),
+            Some("Bob".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(contract.get_profile("bob.near".parse().unwrap()).unwrap().verified);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can do this")]
+    fn test_set_verified_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        testing_env!(get_context("bob.near").build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_verified("bob.near".parse().unwrap(), true);
+    }
+
+    #[test]
+    #[should_panic(expected = "No messaging key registered")]
+    fn test_update_display_name_without_profile() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.update_display_name(Some("Alice".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Display name is already taken")]
+    fn test_duplicate_display_name() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            Some("Alice".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(get_context("mallory.near").build());
+        contract.register_key(
+            BASE64.encode([2u8; 32]),
+            Some("ALICE".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Display name must be at least 2 characters")]
+    fn test_display_name_too_short() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            Some("A".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Display name contains a banned word")]
+    fn test_display_name_banned_substring() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.add_banned_substring("Admin".to_string());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.update_display_name(Some("TheADMIN".to_string()));
+    }
+
+    #[test]
+    fn test_display_name_allowed() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.add_banned_substring("admin".to_string());
+        assert_eq!(contract.get_banned_substrings(), vec!["admin".to_string()]);
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            Some("Al".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.update_display_name(Some("Alice".to_string()));
+        assert_eq!(
+            contract
+                .get_profile("alice.near".parse().unwrap())
+                .unwrap()
+                .display_name
+                .as_deref(),
+            Some("Alice")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Display name must not contain control characters")]
+    fn test_display_name_control_characters() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            Some("Al\nice".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_register_with_signing_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let signing_key = BASE64.encode([9u8; 32]);
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            None,
+            None,
+            Some(signing_key.clone()),
+            None,
+            None,
+        );
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.ed25519_pubkey, Some(signing_key.clone()));
+        assert_eq!(last_event()["data"]["ed25519_pubkey"], signing_key);
+
+        testing_env!(get_context("bob.near").build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        let profile = contract.get_profile("bob.near".parse().unwrap()).unwrap();
+        assert!(profile.ed25519_pubkey.is_none());
+    }
+
+    #[test]
+    fn test_rotation_keeps_signing_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let signing_key = BASE64.encode([9u8; 32]);
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            None,
+            None,
+            Some(signing_key.clone()),
+            None,
+            None,
+        );
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.key_version, 2);
+        assert_eq!(profile.ed25519_pubkey, Some(signing_key.clone()));
+        let event = last_event();
+        assert_eq!(event["event"], "key_rotated");
+        assert_eq!(event["data"]["ed25519_pubkey"], signing_key);
+    }
+
+    #[test]
+    #[should_panic(expected = "Ed25519 pubkey must be 32 bytes")]
+    fn test_register_with_short_signing_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            None,
+            None,
+            Some(BASE64.encode([9u8; 31])),
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_metadata_uri() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let uri = "ipfs://bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi".to_string();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, Some(uri.clone()), None);
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.metadata_uri, Some(uri.clone()));
+
+        // Rotating without a URI keeps the avatar; set_metadata_uri clears it
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.metadata_uri, Some(uri));
+
+        contract.set_metadata_uri(Some("https://example.com/alice.json".to_string()));
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.metadata_uri.as_deref(), Some("https://example.com/alice.json"));
+        assert_eq!(last_event()["event"], "metadata_uri_updated");
+    }
+
+    #[test]
+    #[should_panic(expected = "Metadata URI must start with https:// or ipfs://")]
+    fn test_metadata_uri_bad_scheme() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_metadata_uri(Some("http://example.com/alice.json".to_string()));
+    }
+
+    #[test]
+    fn test_linked_profile() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let path = "alice.near/profile".to_string();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, Some(path.clone()));
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.linked_profile, Some(path.clone()));
+
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.linked_profile, Some(path));
+
+        contract.set_linked_profile(None);
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.linked_profile, None);
+        let event = last_event();
+        assert_eq!(event["event"], "linked_profile_updated");
+        assert!(event["data"]["linked_profile"].is_null());
+    }
+
+    #[test]
+    #[should_panic(expected = "Linked profile must be 1-128 bytes")]
+    fn test_linked_profile_too_long() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_linked_profile(Some("a".repeat(129)));
+    }
+
+    #[test]
+    fn test_revoke_and_reregister_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        contract.revoke_key();
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert!(profile.revoked);
+        assert_eq!(profile.key_version, 1);
+
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert!(!profile.revoked);
+        assert_eq!(profile.key_version, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient key is revoked")]
+    fn test_send_to_revoked() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.revoke_key();
+
+        let context_alice = get_context("alice.near");
+        testing_env!(context_alice.build());
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient profile is inactive")]
+    fn test_send_to_inactive() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_active(false);
+        assert!(contract.has_profile("bob.near".parse().unwrap()));
+        assert!(!contract.is_active("bob.near".parse().unwrap()));
+
+        testing_env!(get_context("alice.near").build());
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_reactivate_profile() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        assert!(contract.is_active("bob.near".parse().unwrap()));
+        contract.set_active(false);
+        contract.set_active(true);
+        assert!(contract.is_active("bob.near".parse().unwrap()));
+
+        testing_env!(get_context("alice.near").build());
+        assert!(contract.can_send("alice.near".parse().unwrap(), "bob.near".parse().unwrap()));
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    /// `MessagingProfile` as the first deployment stored it
+    #[derive(BorshDeserialize, BorshSerialize)]
+    #[borsh(crate = "near_sdk::borsh")]
+    struct LegacyProfile {
+        x25519_pubkey: String,
+        key_version: u32,
+        registered_at: u64,
+        display_name: Option<String>,
+    }
+
+    /// `GroupChat` as the first deployment stored it
+    #[derive(BorshDeserialize, BorshSerialize)]
+    #[borsh(crate = "near_sdk::borsh")]
+    struct LegacyGroup {
+        group_id: String,
+        creator: AccountId,
+        created_at: u64,
+        name: Option<String>,
+    }
+
+    /// `LegacyWhisperContract` with the first deployment's record layouts
+    #[derive(BorshSerialize)]
+    #[borsh(crate = "near_sdk::borsh")]
+    struct LegacyState {
+        profiles: LookupMap<AccountId, LegacyProfile>,
+        groups: LookupMap<String, LegacyGroup>,
+        profile_count: u64,
+        message_count: u64,
+        owner: AccountId,
+    }
+
+    #[test]
+    fn test_legacy_profile_deserializes() {
+        let bytes = borsh::to_vec(&LegacyProfile {
+            x25519_pubkey: BASE64.encode([1u8; 32]),
+            key_version: 3,
+            registered_at: 42,
+            display_name: Some("Alice".to_string()),
+        })
+        .unwrap();
+
+        let profile = MessagingProfile::try_from_slice(&bytes).unwrap();
+        assert_eq!(profile.key_version, 3);
+        assert_eq!(profile.display_name.as_deref(), Some("Alice"));
+        assert!(!profile.revoked);
+        assert!(profile.active);
+    }
+
+    #[test]
+    fn test_legacy_group_round_trips() {
+        let bytes = borsh::to_vec(&LegacyGroup {
+            group_id: "legacy".to_string(),
+            creator: "alice.near".parse().unwrap(),
+            created_at: 42,
+            name: Some("Legacy".to_string()),
+        })
+        .unwrap();
+
+        // Membership is backfilled with the creator on the first read
+        let group = GroupChat::try_from_slice(&bytes).unwrap();
+        assert_eq!(group.members, vec!["alice.near".parse::<AccountId>().unwrap()]);
+        assert_eq!(group.member_count, 1);
+        assert_eq!(group.group_key_version, 1);
+        assert!(group.admins.is_empty());
+        assert!(group.rate_limit.is_none());
+
+        // and written back in the current layout unchanged
+        let reread = GroupChat::try_from_slice(&borsh::to_vec(&group).unwrap()).unwrap();
+        assert_eq!(reread.group_id, "legacy");
+        assert_eq!(reread.created_at, 42);
+        assert_eq!(reread.name.as_deref(), Some("Legacy"));
+        assert_eq!(reread.members, group.members);
+        assert_eq!(reread.member_count, 1);
+        assert_eq!(reread.group_key_version, 1);
+    }
+
+    #[test]
+    fn test_unregister_refunds_deposit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        assert_eq!(contract.profile_count, 1);
+
+        let storage_paid = contract
+            .get_profile("alice.near".parse().unwrap())
+            .unwrap()
+            .storage_paid;
+
+        drop(contract.unregister());
+        assert_eq!(contract.profile_count, 0);
+        assert!(!contract.has_profile("alice.near".parse().unwrap()));
+
+        // The sent counter outlives the profile, so its storage stays paid
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let refund = storage_paid.saturating_sub(WhisperContract::sent_count_storage_cost(&alice));
+        let receipt = get_created_receipts().pop().unwrap();
+        assert_eq!(receipt.receiver_id.to_string(), "alice.near");
+        assert!(matches!(
+            receipt.actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == refund
+        ));
+    }
+
+    #[test]
+    fn test_prune_inactive_profile() {
+        let context = get_context("owner.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+
+        testing_env!(get_context("alice.near").build());
+        contract.register_key(
+            BASE64.encode([1u8; 32]),
+            Some("Alice".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        let storage_paid = contract
+            .get_profile("alice.near".parse().unwrap())
+            .unwrap()
+            .storage_paid;
+
+        let mut context = get_context("owner.near");
+        context.block_timestamp((DEFAULT_PRUNE_AFTER_SECONDS + 1) * 1_000_000_000);
+        testing_env!(context.build());
+        contract.prune_inactive_profiles(vec!["alice.near".parse().unwrap()]);
+
+        assert_eq!(contract.profile_count, 0);
+        assert!(!contract.has_profile("alice.near".parse().unwrap()));
+        let event = last_event();
+        assert_eq!(event["event"], "profile_pruned");
+        assert_eq!(event["data"]["account_id"], "alice.near");
+        // The sent counter outlives the profile, so its storage stays paid
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let refund = storage_paid.saturating_sub(WhisperContract::sent_count_storage_cost(&alice));
+        let receipt = get_created_receipts().pop().unwrap();
+        assert_eq!(receipt.receiver_id.to_string(), "alice.near");
+        assert!(matches!(
+            receipt.actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == refund
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Profile alice.near was used too recently to prune")]
+    fn test_prune_recently_used_profile() {
+        let context = get_context("owner.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        contract.set_prune_threshold(60);
+
+        testing_env!(get_context("bob.near").build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        testing_env!(get_context("alice.near").build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        // Registered long ago, but sent a message recently
+        let mut context = get_context("alice.near");
+        context.block_timestamp(100 * 1_000_000_000);
+        testing_env!(context.build());
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let mut context = get_context("owner.near");
+        context.block_timestamp(120 * 1_000_000_000);
+        testing_env!(context.build());
+        contract.prune_inactive_profiles(vec![
+            "bob.near".parse().unwrap(),
+            "alice.near".parse().unwrap(),
+        ]);
+    }
+
+    #[test]
+    fn test_storage_deposit_and_withdraw() {
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_millinear(50));
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        assert!(contract.storage_balance_of("alice.near".parse().unwrap()).is_none());
+        contract.storage_deposit(None, None);
+
+        let balance = contract.storage_balance_of("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(balance.total.0, NearToken::from_millinear(50).as_yoctonear());
+        assert_eq!(balance.available.0, NearToken::from_millinear(50).as_yoctonear());
+
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_yoctonear(1));
+        testing_env!(context.build());
+        let balance = contract.storage_withdraw(Some(U128(
+            NearToken::from_millinear(20).as_yoctonear(),
+        )));
+        assert_eq!(balance.available.0, NearToken::from_millinear(30).as_yoctonear());
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == NearToken::from_millinear(20)
+        ));
+    }
+
+    #[test]
+    fn test_register_draws_from_storage_balance() {
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_millinear(50));
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.storage_deposit(None, None);
+
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_yoctonear(0));
+        testing_env!(context.build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let storage_paid = contract
+            .get_profile("alice.near".parse().unwrap())
+            .unwrap()
+            .storage_paid;
+        let balance = contract.storage_balance_of("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(balance.total.0, NearToken::from_millinear(50).as_yoctonear());
+        assert_eq!(
+            balance.available.0,
+            NearToken::from_millinear(50).saturating_sub(storage_paid).as_yoctonear()
+        );
+
+        // Only the unused portion can be withdrawn while the profile exists
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_yoctonear(1));
+        testing_env!(context.build());
+        let balance = contract.storage_withdraw(None);
+        assert_eq!(balance.available.0, 0);
+        assert_eq!(balance.total.0, storage_paid.as_yoctonear());
+
+        // Unregistering releases the locked part, minus the sent counter that
+        // outlives the profile, back into the balance
+        drop(contract.unregister());
+        let balance = contract.storage_balance_of("alice.near".parse().unwrap()).unwrap();
+        let retained = WhisperContract::sent_count_storage_cost(&"alice.near".parse().unwrap());
+        assert_eq!(balance.available.0, storage_paid.saturating_sub(retained).as_yoctonear());
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds available storage balance")]
+    fn test_storage_withdraw_too_much() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.storage_deposit(None, None);
+
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_yoctonear(1));
+        testing_env!(context.build());
+        contract.storage_withdraw(Some(U128(NearToken::from_millinear(101).as_yoctonear())));
+    }
+
+    #[test]
+    fn test_register_refunds_excess_deposit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let storage_paid = contract
+            .get_profile("alice.near".parse().unwrap())
+            .unwrap()
+            .storage_paid;
+        assert!(!storage_paid.is_zero());
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "alice.near");
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }]
+                if deposit == NearToken::from_millinear(100).saturating_sub(storage_paid)
+        ));
+    }
+
+    #[test]
+    fn test_register_refund_event() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let storage_paid = contract
+            .get_profile("alice.near".parse().unwrap())
+            .unwrap()
+            .storage_paid;
+
+        let refund = events()
+            .into_iter()
+            .find(|event| event["event"] == "storage_refunded")
+            .unwrap();
+        assert_eq!(refund["data"]["account_id"], "alice.near");
+        assert_eq!(
+            refund["data"]["amount"],
+            NearToken::from_millinear(100)
+                .saturating_sub(storage_paid)
+                .as_yoctonear()
+                .to_string()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage deposit")]
+    fn test_register_insufficient_deposit() {
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_yoctonear(1));
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+    }
+
+    #[test]
+    fn test_registration_deposit_minimum() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_registration_deposit(NearToken::from_millinear(20));
+        assert_eq!(
+            contract.get_stats()["registration_deposit"],
+            NearToken::from_millinear(20).as_yoctonear().to_string()
+        );
+        assert_eq!(
+            contract.storage_cost_estimate(None).0,
+            NearToken::from_millinear(20).as_yoctonear()
+        );
+
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
+        assert_eq!(profile.storage_paid, NearToken::from_millinear(20));
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage deposit")]
+    fn test_registration_deposit_enforced() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_registration_deposit(NearToken::from_millinear(200));
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach at least 101000000000000000000000 yoctoNEAR for storage")]
+    fn test_group_deposit_enforced() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.set_group_deposit(NearToken::from_millinear(100));
+        contract.create_group("g".to_string(), None, "{}".to_string(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "No messaging key registered")]
+    fn test_unregister_without_profile() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.unregister();
+    }
+
+    #[test]
+    fn test_get_profiles() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        testing_env!(get_context("bob.near").build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        let profiles = contract.get_profiles(vec![
+            "bob.near".parse().unwrap(),
+            "alice.near".parse().unwrap(),
+            "nobody.near".parse().unwrap(),
+        ]);
+        assert_eq!(profiles.len(), 3);
+        assert_eq!(profiles[0].as_ref().unwrap().x25519_pubkey, BASE64.encode([2u8; 32]));
+        assert_eq!(profiles[1].as_ref().unwrap().x25519_pubkey, BASE64.encode([1u8; 32]));
+        assert!(profiles[2].is_none());
+    }
+
+    #[test]
+    fn test_profiles_exist() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let exists = contract.profiles_exist(vec![
+            "nobody.near".parse().unwrap(),
+            "alice.near".parse().unwrap(),
+        ]);
+        assert_eq!(exists, vec![false, true]);
+    }
+
+    #[test]
+    fn test_storage_cost_estimate() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let contract = WhisperContract::new();
+        let unnamed = contract.storage_cost_estimate(None).0;
+        let short = contract.storage_cost_estimate(Some("Al".to_string())).0;
+        let long = contract.storage_cost_estimate(Some("Alice Wonderland".to_string())).0;
+        assert!(unnamed > 0);
+        assert!(short > unnamed);
+        assert!(long > short);
+        // The extra bytes are stored in both the profile and the name index
+        assert_eq!(long - short, 2 * 14 * env::storage_byte_cost().as_yoctonear());
+    }
+
+    #[test]
+    fn test_list_profiles() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        for (i, account) in ["alice.near", "bob.near", "carol.near"].iter().enumerate() {
+            testing_env!(get_context(account).build());
+            contract.register_key(BASE64.encode([i as u8; 32]), None, None, None, None, None);
+        }
+
+        let page1 = contract.list_profiles(0, 2);
+        let page2 = contract.list_profiles(2, 2);
+        assert_eq!(page1.len(), 2);
+        assert_eq!(page2.len(), 1);
+        assert_eq!(page1[0].0.to_string(), "alice.near");
+        assert_eq!(page1[1].0.to_string(), "bob.near");
+        assert_eq!(page2[0].0.to_string(), "carol.near");
+        assert!(contract.list_profiles(3, 2).is_empty());
+
+        testing_env!(get_context("alice.near").build());
+        drop(contract.unregister());
+        let all = contract.list_profiles(0, 100);
+        assert_eq!(all.len(), 2);
+        assert!(all.iter().all(|(id, _)| id.as_str() != "alice.near"));
+    }
+
+    #[test]
+    fn test_profiles_registered_after() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        for (i, (account, registered_at)) in
+            [("alice.near", 100), ("bob.near", 200), ("carol.near", 200)].iter().enumerate()
+        {
+            let mut context = get_context(account);
+            context.block_timestamp(*registered_at);
+            testing_env!(context.build());
+            contract.register_key(BASE64.encode([i as u8; 32]), None, None, None, None, None);
+        }
+
+        let cohort = contract.profiles_registered_after(100, 0, 100);
+        let ids: Vec<&str> = cohort.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, vec!["bob.near", "carol.near"]);
+        assert!(contract.profiles_registered_after(200, 0, 100).is_empty());
+        assert_eq!(contract.profiles_registered_after(100, 0, 2).len(), 1);
+
+        // Rotating a key doesn't move alice into a later cohort
+        let mut context = get_context("alice.near");
+        context.block_timestamp(300);
+        testing_env!(context.build());
+        contract.register_key(BASE64.encode([7u8; 32]), None, None, None, None, None);
+        assert_eq!(contract.profiles_registered_after(100, 0, 100).len(), 2);
+    }
+
+    #[test]
+    fn test_send_message() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let context_bob = get_context("bob.near");
+        testing_env!(context_bob.build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        let context_alice = get_context("alice.near");
+        testing_env!(context_alice.build());
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 1);
+    }
+
+    fn batch_item(to: &str) -> BatchMessageItem {
+        BatchMessageItem {
+            to: to.parse().unwrap(),
+            encrypted_body: "encrypted".to_string(),
+            nonce: test_nonce(),
+            recipient_key_version: 1,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn test_batch_send_message() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        for (i, account) in ["bob.near", "carol.near", "dave.near"].iter().enumerate() {
+            testing_env!(get_context(account).build());
+            contract.register_key(BASE64.encode([i as u8; 32]), None, None, None, None, None);
+        }
+
+        testing_env!(get_context("alice.near").build());
+        contract.batch_send_message(vec![
+            batch_item("bob.near"),
+            batch_item("carol.near"),
+            batch_item("dave.near"),
+        ]);
+        assert_eq!(contract.message_count, 3);
+
+        let ids: Vec<u64> = events()
+            .iter()
+            .map(|event| event["data"]["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient has no registered messaging key")]
+    fn test_batch_send_rejects_unregistered() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        contract.batch_send_message(vec![batch_item("bob.near"), batch_item("nobody.near")]);
+    }
+
+    #[test]
+    fn test_send_with_expiry() {
+        let mut context = get_context("alice.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let mut context = get_context("bob.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            Some(5_000),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["expires_at"], 5_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "expires_at must be in the future")]
+    fn test_send_with_past_expiry() {
+        let mut context = get_context("alice.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let mut context = get_context("bob.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            Some(999),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_send_with_content_type() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            Some("image/png".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["content_type"], "image/png");
+    }
+
+    #[test]
+    fn test_send_with_category() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            Some("social".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["category"], "social");
+    }
+
+    #[test]
+    fn test_client_version_in_events() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            Some("web/2.1.0".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["client_version"], "web/2.1.0");
+
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            Some("ios/1.4".to_string()),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["client_version"], "ios/1.4");
+    }
+
+    #[test]
+    #[should_panic(expected = "Client version must be at most 16 bytes")]
+    fn test_overlong_client_version() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            Some("v".repeat(17)),
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_send_with_thread_depth() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(MAX_THREAD_DEPTH),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["thread_depth"], MAX_THREAD_DEPTH);
+    }
+
+    #[test]
+    #[should_panic(expected = "Thread depth must be at most 64")]
+    fn test_send_with_excessive_thread_depth() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            Some("1".to_string()),
+            None,
+            None,
+            None,
+            None,
+            Some(MAX_THREAD_DEPTH + 1),
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Category must be at most 32 bytes")]
+    fn test_send_with_overlong_category() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            Some("c".repeat(33)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported content type: application/x-unknown")]
+    fn test_send_with_unknown_content_type() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            Some("application/x-unknown".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_send_with_compression() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("zstd".to_string()),
+        );
+        assert_eq!(last_event()["data"]["compression"], "zstd");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unsupported compression: lzma")]
+    fn test_group_message_with_unknown_compression() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            Some("lzma".to_string()),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot message yourself")]
+    fn test_send_to_self_rejected() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_send_to_self_when_allowed() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_allow_self_message(true);
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "note to self".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 1);
+    }
+
+    #[test]
+    fn test_send_message_returns_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        for expected in 1..=2 {
+            let id = contract.send_message(
+                "alice.near".parse().unwrap(),
+                "encrypted".to_string(),
+                test_nonce(),
+                1,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+            assert_eq!(id, expected);
+            assert_eq!(last_event()["data"]["id"], id);
+        }
+
+        let id = contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(id, 3);
+        assert_eq!(last_event()["data"]["id"], id);
+    }
+
+    #[test]
+    fn test_strict_reply_validation_accepts_known_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_strict_reply_validation(true);
+
+        testing_env!(get_context("bob.near").build());
+        let parent = contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "reply".to_string(),
+            test_nonce(),
+            1,
+            Some(parent.to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 2);
+    }
+
+    #[test]
+    fn test_strict_key_version_accepts_current() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_strict_key_version(true);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            2,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Stale recipient key version")]
+    fn test_strict_key_version_rejects_stale() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_strict_key_version(true);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "reply_to references a future message")]
+    fn test_strict_reply_validation_rejects_future_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_strict_reply_validation(true);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "reply".to_string(),
+            test_nonce(),
+            1,
+            Some("42".to_string()),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_sent_count() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        for _ in 0..2 {
+            contract.send_message(
+                "bob.near".parse().unwrap(),
+                "encrypted".to_string(),
+                test_nonce(),
+                1,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+        }
+        assert_eq!(contract.get_sent_count("alice.near".parse().unwrap()), 2);
+        assert_eq!(contract.get_sent_count("bob.near".parse().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_send_with_client_msg_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let client_msg_id = "3f2b8c1e-7a4d-4e0b-9c5f-1d2e3f4a5b6c".to_string();
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(client_msg_id.clone()),
+            None,
+        );
+        assert_eq!(last_event()["data"]["client_msg_id"], client_msg_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "client_msg_id must be at most 64 bytes")]
+    fn test_send_with_long_client_msg_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some("x".repeat(65)),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_send_with_body_hash() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let body_hash = BASE64.encode(env::sha256(b"encrypted"));
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(body_hash.clone()),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["body_hash"], body_hash);
+    }
+
+    #[test]
+    #[should_panic(expected = "body_hash must be a 32-byte SHA-256 digest")]
+    fn test_send_with_short_body_hash() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(BASE64.encode([0u8; 16])),
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_auto_reply() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_auto_reply(Some("Out until Monday".to_string()));
+        let event = last_event();
+        assert_eq!(event["event"], "auto_reply_updated");
+        assert_eq!(event["data"]["message"], "Out until Monday");
+
+        testing_env!(get_context("alice.near").build());
+        let id = contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        let event = last_event();
+        assert_eq!(event["event"], "auto_reply");
+        assert_eq!(event["data"]["from"], "bob.near");
+        assert_eq!(event["data"]["to"], "alice.near");
+        assert_eq!(event["data"]["message"], "Out until Monday");
+        assert_eq!(event["data"]["in_reply_to"], id);
+
+        testing_env!(get_context("bob.near").build());
+        contract.set_auto_reply(None);
+        testing_env!(get_context("alice.near").build());
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["event"], "message");
+    }
+
+    #[test]
+    fn test_register_and_send() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        let id = contract.register_and_send(
+            BASE64.encode([1u8; 32]),
+            Some("Alice".to_string()),
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+        );
+
+        assert!(contract.has_profile("alice.near".parse().unwrap()));
+        assert_eq!(contract.profile_count, 2);
+        let logged = events();
+        let names: Vec<&str> = logged.iter().filter_map(|e| e["event"].as_str()).collect();
+        assert_eq!(names, vec!["storage_refunded", "key_registered", "message"]);
+        let message = logged.last().unwrap();
+        assert_eq!(message["data"]["id"], id);
+        assert_eq!(message["data"]["from"], "alice.near");
+        assert_eq!(message["data"]["to"], "bob.near");
+    }
+
+    #[test]
+    fn test_unpaid_sender_adds_no_storage() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        // alice never registered or deposited, so nothing keyed to her is written
+        testing_env!(get_context("alice.near").build());
+        let storage_before = env::storage_usage();
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(env::storage_usage(), storage_before);
+        assert_eq!(contract.get_inbox_seq("bob.near".parse().unwrap()), 1);
+        let alice: AccountId = "alice.near".parse().unwrap();
+        assert_eq!(contract.get_sent_count(alice.clone()), 0);
+        assert_eq!(contract.conversation_count(alice, "bob.near".parse().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_inbox_seq() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        assert_eq!(contract.get_inbox_seq("bob.near".parse().unwrap()), 0);
+
+        for expected in 1..=2u64 {
+            testing_env!(get_context("alice.near").build());
+            contract.send_message(
+                "bob.near".parse().unwrap(),
+                "encrypted".to_string(),
+                test_nonce(),
+                1,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+            assert_eq!(last_event()["data"]["recipient_seq"], expected);
+        }
+        assert_eq!(contract.get_inbox_seq("bob.near".parse().unwrap()), 2);
+        assert_eq!(contract.get_inbox_seq("alice.near".parse().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_conversation_count() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        for sent in 0..2 {
+            // The first message pays for the pair out of its deposit
+            testing_env!(get_context("alice.near").build());
+            let storage_before = env::storage_usage();
+            contract.send_message(
+                "bob.near".parse().unwrap(),
+                "encrypted".to_string(),
+                test_nonce(),
+                1,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+            let storage_used = env::storage_usage() - storage_before;
+            assert_eq!(storage_used > 0, sent == 0);
+            let charged = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+            let excess = NearToken::from_millinear(100).saturating_sub(charged);
+            assert!(matches!(
+                get_created_receipts()[0].actions[..],
+                [MockAction::Transfer { deposit, .. }] if deposit == excess
+            ));
+        }
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        assert_eq!(contract.conversation_count(alice.clone(), bob.clone()), 2);
+        assert_eq!(contract.conversation_count(bob, alice), 0);
+    }
+
+    #[test]
+    fn test_conversation_count_without_deposit() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        for with_balance in [false, true] {
+            if with_balance {
+                testing_env!(get_context("alice.near").build());
+                contract.storage_deposit(None, None);
+            }
+            let mut context = get_context("alice.near");
+            context.attached_deposit(NearToken::from_yoctonear(0));
+            testing_env!(context.build());
+            contract.send_message(
+                bob.clone(),
+                "encrypted".to_string(),
+                test_nonce(),
+                1,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+            // Unfunded pairs go uncounted until the balance covers them
+            let expected = u64::from(with_balance);
+            assert_eq!(contract.conversation_count(alice.clone(), bob.clone()), expected);
+        }
+    }
+
+    #[test]
+    fn test_last_message_id() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        assert_eq!(contract.last_message_id(), 0);
+
+        testing_env!(get_context("alice.near").build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let to_bob = contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(get_context("bob.near").build());
+        let to_alice = contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.last_message_id(), to_alice);
+        assert_eq!(
+            contract.last_message_id_for_recipient("bob.near".parse().unwrap()),
+            to_bob
+        );
+        assert_eq!(
+            contract.last_message_id_for_recipient("alice.near".parse().unwrap()),
+            to_alice
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Encrypted body too large")]
+    fn test_send_oversized_body() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "A".repeat(MAX_ENCRYPTED_BODY_LEN + 1),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Nonce must be 24 bytes")]
+    fn test_send_wrong_length_nonce() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            BASE64.encode([7u8; 12]),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient has no registered messaging key")]
+    fn test_send_to_unregistered() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        contract.send_message(
+            "nobody.near".parse().unwrap(),
+            "data".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_send_message_with_ft() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.add_ft_token("usdc.near".parse().unwrap());
+
+        let context_token = get_context("usdc.near");
+        testing_env!(context_token.build());
+        let msg = serde_json::json!({
+            "to": "bob.near",
+            "encrypted_body": "encrypted",
+            "nonce": test_nonce(),
+            "recipient_key_version": 1,
+        });
+        drop(contract.ft_on_transfer("alice.near".parse().unwrap(), U128(500), msg.to_string()));
+        assert_eq!(contract.message_count, 1);
+
+        let event = last_event();
+        assert_eq!(event["event"], "message");
+        assert_eq!(event["data"]["from"], "alice.near");
+        assert_eq!(event["data"]["to"], "bob.near");
+        assert_eq!(event["data"]["payment"]["token"], "usdc.near");
+        assert_eq!(event["data"]["payment"]["amount"], "500");
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is not accepted for payment messages")]
+    fn test_ft_on_transfer_from_untrusted_caller() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.add_ft_token("usdc.near".parse().unwrap());
+
+        // Anyone can call the receiver directly and claim any sender_id
+        testing_env!(get_context("mallory.near").build());
+        let msg = serde_json::json!({
+            "to": "bob.near",
+            "encrypted_body": "encrypted",
+            "nonce": test_nonce(),
+            "recipient_key_version": 1,
+        });
+        drop(contract.ft_on_transfer("alice.near".parse().unwrap(), U128(500), msg.to_string()));
+    }
+
+    #[test]
+    fn test_ft_payment_failure_emits_event() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let refunded = contract.on_ft_payment_complete(
+            1,
+            "usdc.near".parse().unwrap(),
+            "alice.near".parse().unwrap(),
+            "bob.near".parse().unwrap(),
+            U128(500),
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(refunded, U128(500));
+
+        let event = last_event();
+        assert_eq!(event["event"], "ft_payment_failed");
+        assert_eq!(event["data"]["message_id"], 1);
+        assert_eq!(event["data"]["amount"], "500");
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient has no registered messaging key")]
+    fn test_send_message_with_ft_to_unregistered() {
+        let context = get_context("usdc.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.add_ft_token("usdc.near".parse().unwrap());
+        let msg = serde_json::json!({
+            "to": "nobody.near",
+            "encrypted_body": "encrypted",
+            "nonce": test_nonce(),
+            "recipient_key_version": 1,
+        });
+        contract.ft_on_transfer("alice.near".parse().unwrap(), U128(500), msg.to_string());
+    }
+
+    #[test]
+    fn test_payment_message_priority() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        let _ = contract.send_message_with_payment(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        );
+        let event = last_event();
+        assert_eq!(event["data"]["priority"], PRIORITY_HIGH);
+        assert_eq!(
+            event["data"]["payment"]["amount"],
+            NearToken::from_millinear(100).as_yoctonear().to_string()
+        );
+
+        contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["priority"], PRIORITY_LOW);
+
+        contract.send_message_checked(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        );
+        assert_eq!(last_event()["data"]["priority"], PRIORITY_LOW);
+
+        contract.batch_send_message(vec![batch_item("bob.near")]);
+        assert_eq!(last_event()["data"]["priority"], PRIORITY_LOW);
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient does not accept payments")]
+    fn test_payment_rejected_when_disabled() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_accepts_payments(false);
+        assert!(!contract.get_profile("bob.near".parse().unwrap()).unwrap().accepts_payments);
+
+        testing_env!(get_context("alice.near").build());
+        let _ = contract.send_message_with_payment(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_payment_allowed_when_reenabled() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        assert!(contract.get_profile("bob.near".parse().unwrap()).unwrap().accepts_payments);
+        contract.set_accepts_payments(false);
+        contract.set_accepts_payments(true);
+
+        testing_env!(get_context("alice.near").build());
+        drop(contract.send_message_with_payment(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        ));
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "bob.near");
+    }
+
+    #[test]
+    fn test_transfer_gas_override() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_transfer_gas(Some(Gas::from_tgas(20)));
+
+        testing_env!(get_context("alice.near").build());
+        drop(contract.send_message_with_payment(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        ));
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "bob.near");
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas left for the payment transfer")]
+    fn test_transfer_gas_exceeds_prepaid() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_transfer_gas(Some(Gas::from_tgas(400)));
+
+        testing_env!(get_context("alice.near").build());
+        drop(contract.send_message_with_payment(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        ));
+    }
+
+    #[test]
+    fn test_total_value_transferred() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let message_id = send_test_escrow(&mut contract);
+        // Escrows only count once claimed
+        assert_eq!(contract.get_stats()["total_value_transferred"], "0");
+
+        drop(contract.send_message_with_payment(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        ));
+        let paid = NearToken::from_millinear(50).as_yoctonear();
+        assert_eq!(contract.get_stats()["total_value_transferred"], paid.to_string());
+
+        testing_env!(get_context("bob.near").build());
+        drop(contract.claim_payment(message_id));
+        let total = NearToken::from_millinear(100).as_yoctonear();
+        assert_eq!(contract.get_stats()["total_value_transferred"], total.to_string());
+        assert_eq!(contract.get_value_received("bob.near".parse().unwrap()).0, total);
+        assert_eq!(contract.get_value_received("alice.near".parse().unwrap()).0, 0);
+    }
+
+    #[test]
+    fn test_value_received_allocated_at_registration() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let message_id = send_test_escrow(&mut contract);
+        let bob: AccountId = "bob.near".parse().unwrap();
+        assert_eq!(contract.value_received.get(&bob), Some(NearToken::from_yoctonear(0)));
+
+        // Claiming after unregistering doesn't recreate the tally
+        testing_env!(get_context("bob.near").build());
+        drop(contract.unregister());
+        assert!(contract.value_received.get(&bob).is_none());
+        drop(contract.claim_payment(message_id));
+        assert!(contract.value_received.get(&bob).is_none());
+        assert_eq!(contract.get_value_received(bob).0, 0);
+    }
+
+    fn send_test_escrow(contract: &mut WhisperContract) -> u64 {
+        send_escrow(contract, false)
+    }
+
+    fn send_test_escrow_with_ack(contract: &mut WhisperContract) -> u64 {
+        send_escrow(contract, true)
+    }
+
+    fn send_escrow(contract: &mut WhisperContract, require_ack: bool) -> u64 {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_millinear(50));
+        testing_env!(context.build());
+        contract.send_message_escrow(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            Some(require_ack),
+        )
+    }
+
+    #[test]
+    fn test_acknowledge_then_claim() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let message_id = send_test_escrow_with_ack(&mut contract);
+        assert_eq!(last_event()["data"]["payment"]["require_ack"], true);
+
+        testing_env!(get_context("bob.near").build());
+        contract.acknowledge(message_id);
+        let event = last_event();
+        assert_eq!(event["event"], "message_acknowledged");
+        assert_eq!(event["data"]["message_id"], message_id);
+        assert_eq!(event["data"]["to"], "bob.near");
+
+        drop(contract.claim_payment(message_id));
+        assert_eq!(last_event()["event"], "payment_claimed");
+        assert!(contract.escrows.get(&message_id).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Acknowledge the message before claiming")]
+    fn test_claim_before_acknowledge() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let message_id = send_test_escrow_with_ack(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        drop(contract.claim_payment(message_id));
+    }
+
+    #[test]
+    fn test_claim_escrow_payment() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let message_id = send_test_escrow(&mut contract);
+
+        let event = last_event();
+        assert_eq!(event["data"]["id"], message_id);
+        assert_eq!(event["data"]["payment"]["status"], "escrow");
+        assert_eq!(event["data"]["payment"]["amount"], "50000000000000000000000");
+        assert!(get_created_receipts().is_empty());
+
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+        drop(contract.claim_payment(message_id));
+
+        assert_eq!(last_event()["event"], "payment_claimed");
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "bob.near");
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == NearToken::from_millinear(50)
+        ));
+        assert!(contract.escrows.get(&message_id).is_none());
+    }
+
+    #[test]
+    fn test_refund_escrow_after_timeout() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let message_id = send_test_escrow(&mut contract);
+
+        let mut context = get_context("alice.near");
+        context.block_timestamp(ESCROW_TIMEOUT_NS);
+        testing_env!(context.build());
+        drop(contract.refund_payment(message_id));
+
+        assert_eq!(last_event()["event"], "payment_refunded");
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "alice.near");
+        assert!(contract.escrows.get(&message_id).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Escrow has not timed out yet")]
+    fn test_refund_escrow_before_timeout() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let message_id = send_test_escrow(&mut contract);
+
+        let mut context = get_context("alice.near");
+        context.block_timestamp(ESCROW_TIMEOUT_NS - 1);
+        testing_env!(context.build());
+        contract.refund_payment(message_id);
+    }
+
+    #[test]
+    fn test_react() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let message_id = contract.send_message(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(get_context("bob.near").build());
+        contract.react(message_id, "alice.near".parse().unwrap(), "👍".to_string());
+        assert_eq!(contract.message_count, 1);
+
+        let event = last_event();
+        assert_eq!(event["event"], "reaction");
+        assert_eq!(event["data"]["from"], "bob.near");
+        assert_eq!(event["data"]["to"], "alice.near");
+        assert_eq!(event["data"]["message_id"], message_id);
+        assert_eq!(event["data"]["reaction"], "👍");
+    }
+
+    #[test]
+    fn test_reaction_counts() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        send_to_alice(&mut contract);
+        let alice: AccountId = "alice.near".parse().unwrap();
+        contract.react(1, alice.clone(), "👍".to_string());
+        contract.react(1, alice.clone(), "👍".to_string());
+        contract.react(1, alice, "🎉".to_string());
+
+        assert_eq!(contract.get_reactions(1), serde_json::json!({ "👍": 2, "🎉": 1 }));
+        assert_eq!(contract.get_reactions(2), serde_json::json!({}));
+    }
+
+    #[test]
+    #[should_panic(expected = "Message already has 20 distinct reactions")]
+    fn test_reaction_counts_bounded() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        send_to_alice(&mut contract);
+        for i in 0..=MAX_DISTINCT_REACTIONS {
+            contract.react(1, "alice.near".parse().unwrap(), format!("r{}", i));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Reaction must be 1-16 bytes")]
+    fn test_react_oversized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.react(1, "alice.near".parse().unwrap(), "x".repeat(17));
+    }
+
+    #[test]
+    fn test_react_ignores_inbound_rate_limit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_rate_limit(1, 60);
+
+        testing_env!(get_context("bob.near").build());
+        send_to_alice(&mut contract);
+        let alice: AccountId = "alice.near".parse().unwrap();
+        assert!(!contract.can_send("bob.near".parse().unwrap(), alice.clone()));
+        for _ in 0..2 {
+            contract.react(1, alice.clone(), "+1".to_string());
+        }
+        assert_eq!(contract.inbound_windows.get(&alice).unwrap().1, 1);
+        assert_eq!(contract.get_reactions(1)["+1"], 2);
+    }
+
+    #[test]
+    fn test_typing() {
+        let mut context = get_context("alice.near");
+        context.block_timestamp(42);
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        let mut context = get_context("bob.near");
+        context.block_timestamp(42);
+        testing_env!(context.build());
+        contract.typing("alice.near".parse().unwrap());
+
+        let event = last_event();
+        assert_eq!(event["event"], "typing");
+        assert_eq!(event["data"]["from"], "bob.near");
+        assert_eq!(event["data"]["to"], "alice.near");
+        assert_eq!(event["data"]["timestamp"], 42);
+        assert_eq!(contract.message_count, 0);
+        assert_eq!(contract.get_sent_count("bob.near".parse().unwrap()), 0);
+    }
+
+    #[test]
+    fn test_typing_ignores_inbound_rate_limit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_rate_limit(1, 60);
+
+        testing_env!(get_context("bob.near").build());
+        send_to_alice(&mut contract);
+        let alice: AccountId = "alice.near".parse().unwrap();
+        contract.typing(alice.clone());
+        assert_eq!(last_event()["event"], "typing");
+        assert_eq!(contract.inbound_windows.get(&alice).unwrap().1, 1);
+    }
+
+    fn schedule_test_message(contract: &mut WhisperContract) -> u64 {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.schedule_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            1_000,
+        )
+    }
+
+    #[test]
+    fn test_schedule_message() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let schedule_id = schedule_test_message(&mut contract);
+
+        let pending = contract.get_scheduled_message(schedule_id).unwrap();
+        assert_eq!(pending.to.as_str(), "alice.near");
+        assert_eq!(pending.deliver_at, 1_000);
+        let event = last_event();
+        assert_eq!(event["event"], "message_scheduled");
+        assert_eq!(event["data"]["schedule_id"], schedule_id);
+        assert_eq!(contract.message_count, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Scheduled message is not due yet")]
+    fn test_deliver_scheduled_too_early() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let schedule_id = schedule_test_message(&mut contract);
+
+        let mut context = get_context("cron.near");
+        context.block_timestamp(999);
+        testing_env!(context.build());
+        contract.deliver_scheduled(schedule_id);
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient charges an inbound fee; use send_message")]
+    fn test_deliver_scheduled_rechecks_inbound_fee() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let schedule_id = schedule_test_message(&mut contract);
+
+        testing_env!(get_context("alice.near").build());
+        contract.set_inbound_fee(Some(NearToken::from_millinear(10)));
+
+        let mut context = get_context("cron.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+        contract.deliver_scheduled(schedule_id);
+    }
+
+    #[test]
+    fn test_deliver_scheduled_on_time() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        let schedule_id = schedule_test_message(&mut contract);
+
+        let mut context = get_context("cron.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+        let message_id = contract.deliver_scheduled(schedule_id);
+        assert_eq!(message_id, 1);
+        assert!(contract.get_scheduled_message(schedule_id).is_none());
+
+        let event = last_event();
+        assert_eq!(event["event"], "message");
+        assert_eq!(event["data"]["from"], "bob.near");
+        assert_eq!(event["data"]["to"], "alice.near");
+        assert_eq!(event["data"]["schedule_id"], schedule_id);
+    }
+
+    #[test]
+    fn test_delete_message() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("bob.near").build());
+        let id = contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.delete_message(id, "alice.near".parse().unwrap());
+
+        let event = last_event();
+        assert_eq!(event["event"], "message_deleted");
+        assert_eq!(event["data"]["id"], id);
+        assert_eq!(event["data"]["to"], "alice.near");
+        assert_eq!(event["data"]["deleted_by"], "bob.near");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown message id")]
+    fn test_delete_unknown_message() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.delete_message(1, "alice.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_mark_read() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.mark_read(vec![3, 5, 8], "alice.near".parse().unwrap());
+
+        let event = last_event();
+        assert_eq!(event["event"], "messages_read");
+        assert_eq!(event["data"]["reader"], "bob.near");
+        assert_eq!(event["data"]["from"], "alice.near");
+        assert_eq!(event["data"]["message_ids"], serde_json::json!([3, 5, 8]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot mark more than 100 messages at once")]
+    fn test_mark_read_too_many() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.mark_read((1..=101).collect(), "alice.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_block_and_unblock() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+
+        testing_env!(get_context("alice.near").build());
+        contract.block("bob.near".parse().unwrap());
+        assert!(contract.is_blocked("alice.near".parse().unwrap(), "bob.near".parse().unwrap()));
+        let excess = match get_created_receipts()[0].actions[..] {
+            [MockAction::Transfer { deposit, .. }] => deposit,
+            _ => panic!("expected a refund of the unused deposit"),
+        };
+        let charged = NearToken::from_millinear(100).saturating_sub(excess);
+        assert!(!charged.is_zero());
+
+        // Unblocking frees the entry and refunds what block charged
+        testing_env!(get_context("alice.near").build());
+        contract.unblock("bob.near".parse().unwrap());
+        assert!(!contract.is_blocked("alice.near".parse().unwrap(), "bob.near".parse().unwrap()));
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == charged
+        ));
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Sender is blocked by recipient")]
+    fn test_blocked_sender_rejected() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.block("bob.near".parse().unwrap());
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_get_profile_with_relationship() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.block("bob.near".parse().unwrap());
+        contract.add_contact("carol.near".parse().unwrap());
+
+        let view = contract.get_profile_with_relationship(
+            "alice.near".parse().unwrap(),
+            "bob.near".parse().unwrap(),
+        );
+        assert_eq!(view["profile"]["x25519_pubkey"], BASE64.encode([1u8; 32]));
+        assert_eq!(view["blocked_by_owner"], true);
+        assert_eq!(view["is_contact_of_owner"], false);
+
+        let view = contract.get_profile_with_relationship(
+            "alice.near".parse().unwrap(),
+            "carol.near".parse().unwrap(),
+        );
+        assert_eq!(view["blocked_by_owner"], false);
+        assert_eq!(view["is_contact_of_owner"], true);
+
+        let view = contract.get_profile_with_relationship(
+            "dave.near".parse().unwrap(),
+            "alice.near".parse().unwrap(),
+        );
+        assert!(view["profile"].is_null());
+    }
+
+    #[test]
+    fn test_contacts_only_accepts_contact() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_contacts_only(true);
+        contract.add_contact("bob.near".parse().unwrap());
+        assert!(contract.is_contact("alice.near".parse().unwrap(), "bob.near".parse().unwrap()));
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 1);
+    }
+
+    #[test]
+    fn test_add_and_clear_contacts() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let owner: AccountId = "alice.near".parse().unwrap();
+        let contacts: Vec<AccountId> = ["bob.near", "carol.near", "dave.near"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let storage_before = env::storage_usage();
+        contract.add_contacts(contacts.clone());
+        for contact in &contacts {
+            assert!(contract.is_contact(owner.clone(), contact.clone()));
+        }
+        assert_eq!(last_event()["event"], "contacts_added");
+        let storage_used = env::storage_usage() - storage_before;
+        let charged = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        let excess = NearToken::from_millinear(100).saturating_sub(charged);
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == excess
+        ));
+
+        testing_env!(get_context("alice.near").build());
+        contract.clear_contacts();
+        let receipts = get_created_receipts();
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == charged
+        ));
+        for contact in &contacts {
+            assert!(!contract.is_contact(owner.clone(), contact.clone()));
+        }
+        let event = last_event();
+        assert_eq!(event["event"], "contacts_cleared");
+        assert_eq!(event["data"]["count"], 3);
+    }
+
+    #[test]
+    fn test_add_and_remove_contact_refunds() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let storage_before = env::storage_usage();
+        contract.add_contact("bob.near".parse().unwrap());
+        let storage_used = env::storage_usage() - storage_before;
+        let charged = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        let excess = NearToken::from_millinear(100).saturating_sub(charged);
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == excess
+        ));
+
+        testing_env!(get_context("alice.near").build());
+        contract.remove_contact("bob.near".parse().unwrap());
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == charged
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "At most 100 contacts per call")]
+    fn test_add_contacts_over_cap() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let contacts = (0..101).map(|i| format!("user{}.near", i).parse().unwrap()).collect();
+        contract.add_contacts(contacts);
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient only accepts messages from contacts")]
+    fn test_contacts_only_rejects_stranger() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_contacts_only(true);
+        contract.add_contact("bob.near".parse().unwrap());
+
+        testing_env!(get_context("carol.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    fn send_checked(contract: &mut WhisperContract, to: &str) -> SendResult {
+        contract.send_message_checked(
+            to.parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_send_message_checked() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.block("carol.near".parse().unwrap());
+
+        testing_env!(get_context("bob.near").build());
+        assert_eq!(send_checked(&mut contract, "alice.near"), SendResult::Sent { id: 1 });
+        assert_eq!(last_event()["data"]["id"], 1);
+        assert_eq!(
+            send_checked(&mut contract, "nobody.near"),
+            SendResult::RecipientNotRegistered
+        );
+        assert_eq!(send_checked(&mut contract, "bob.near"), SendResult::SelfMessage);
+
+        testing_env!(get_context("carol.near").build());
+        assert_eq!(send_checked(&mut contract, "alice.near"), SendResult::Blocked);
+        assert_eq!(contract.message_count, 1);
+    }
+
+    #[test]
+    fn test_send_message_checked_recipient_rules() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_contacts_only(true);
+
+        testing_env!(get_context("bob.near").build());
+        assert_eq!(send_checked(&mut contract, "alice.near"), SendResult::ContactsOnly);
+
+        testing_env!(get_context("alice.near").build());
+        contract.set_contacts_only(false);
+        contract.set_inbound_fee(Some(NearToken::from_millinear(5)));
+        testing_env!(get_context("bob.near").build());
+        assert_eq!(
+            send_checked(&mut contract, "alice.near"),
+            SendResult::FeeRequired {
+                amount: U128(NearToken::from_millinear(5).as_yoctonear())
+            }
+        );
+
+        testing_env!(get_context("alice.near").build());
+        contract.set_inbound_fee(None);
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        contract.set_strict_key_version(true);
+        testing_env!(get_context("bob.near").build());
+        assert_eq!(send_checked(&mut contract, "alice.near"), SendResult::StaleKeyVersion);
+
+        testing_env!(get_context("alice.near").build());
+        contract.revoke_key();
+        testing_env!(get_context("bob.near").build());
+        assert_eq!(send_checked(&mut contract, "alice.near"), SendResult::RecipientRevoked);
+        assert_eq!(contract.message_count, 0);
+        assert!(events().is_empty());
+    }
+
+    #[test]
+    fn test_can_send() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let bob: AccountId = "bob.near".parse().unwrap();
+        let carol: AccountId = "carol.near".parse().unwrap();
+
+        // Unregistered recipient
+        assert!(!contract.can_send(bob.clone(), alice.clone()));
+
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        assert!(contract.can_send(bob.clone(), alice.clone()));
+
+        // Blocked sender
+        contract.block(carol.clone());
+        assert!(!contract.can_send(carol.clone(), alice.clone()));
+        contract.unblock(carol.clone());
+
+        // Contacts-only recipient
+        contract.set_contacts_only(true);
+        contract.add_contact(bob.clone());
+        assert!(contract.can_send(bob.clone(), alice.clone()));
+        assert!(!contract.can_send(carol.clone(), alice.clone()));
+        contract.set_contacts_only(false);
+
+        // Revoked key
+        contract.revoke_key();
+        assert!(!contract.can_send(bob, alice));
+    }
+
+    #[test]
+    fn test_inbound_fee_enforced() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_fee(Some(NearToken::from_millinear(30)));
+        assert_eq!(
+            contract.get_inbound_fee("alice.near".parse().unwrap()),
+            Some(U128(NearToken::from_millinear(30).as_yoctonear()))
+        );
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(
+            last_event()["data"]["inbound_fee"],
+            NearToken::from_millinear(30).as_yoctonear().to_string()
+        );
+
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].receiver_id.to_string(), "alice.near");
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == NearToken::from_millinear(30)
+        ));
+        assert_eq!(receipts[1].receiver_id.to_string(), "bob.near");
+        assert!(matches!(
+            receipts[1].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == NearToken::from_millinear(70)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "for the recipient's inbound fee")]
+    fn test_inbound_fee_below_fee_rejected() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_fee(Some(NearToken::from_millinear(200)));
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_inbound_fee_contact_exempt() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_fee(Some(NearToken::from_millinear(200)));
+        contract.add_contact("bob.near".parse().unwrap());
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert!(last_event()["data"]["inbound_fee"].is_null());
+
+        // The whole attached deposit goes back to the sender
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "bob.near");
+    }
+
+    fn send_to_alice(contract: &mut WhisperContract) {
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Recipient inbox rate limit exceeded")]
+    fn test_inbound_rate_limit_exceeded() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_rate_limit(2, 60);
+
+        testing_env!(get_context("bob.near").build());
+        send_to_alice(&mut contract);
+        send_to_alice(&mut contract);
+        send_to_alice(&mut contract);
+    }
+
+    #[test]
+    fn test_can_send_respects_inbound_rate_limit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_rate_limit(1, 60);
+
+        testing_env!(get_context("bob.near").build());
+        let alice: AccountId = "alice.near".parse().unwrap();
+        assert!(contract.can_send("bob.near".parse().unwrap(), alice.clone()));
+        send_to_alice(&mut contract);
+        assert!(!contract.can_send("bob.near".parse().unwrap(), alice));
+    }
+
+    #[test]
+    fn test_inbound_rate_limit_covers_checked_and_payment_sends() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_rate_limit(2, 3600);
+
+        testing_env!(get_context("bob.near").build());
+        assert!(matches!(send_checked(&mut contract, "alice.near"), SendResult::Sent { .. }));
+        drop(contract.send_message_with_payment(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        ));
+        assert_eq!(send_checked(&mut contract, "alice.near"), SendResult::RateLimited);
+        assert_eq!(contract.get_inbox_seq("alice.near".parse().unwrap()), 2);
+    }
+
+    #[test]
+    fn test_inbound_rate_limit_window_resets() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_inbound_rate_limit(2, 60);
+
+        testing_env!(get_context("bob.near").build());
+        send_to_alice(&mut contract);
+        send_to_alice(&mut contract);
+
+        let mut context = get_context("carol.near");
+        context.block_timestamp(60 * 1_000_000_000);
+        testing_env!(context.build());
+        send_to_alice(&mut contract);
+        send_to_alice(&mut contract);
+        assert_eq!(contract.get_inbox_seq("alice.near".parse().unwrap()), 4);
+    }
+
+    #[test]
+    fn test_inbound_rate_limit_window_is_prepaid() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let paid = contract.profiles.get(&alice).unwrap().storage_paid;
+        let storage_before = env::storage_usage();
+        contract.set_inbound_rate_limit(2, 60);
+        let storage_used = env::storage_usage() - storage_before;
+        let charged = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        assert!(contract.inbound_windows.get(&alice).is_some());
+        assert_eq!(
+            contract.profiles.get(&alice).unwrap().storage_paid,
+            paid.saturating_add(charged)
+        );
+
+        testing_env!(get_context("bob.near").build());
+        send_to_alice(&mut contract);
+        assert_eq!(contract.inbound_windows.get(&alice).unwrap().1, 1);
+
+        testing_env!(get_context("alice.near").build());
+        contract.set_inbound_rate_limit(0, 0);
+        assert!(contract.inbound_windows.get(&alice).is_none());
+        assert_eq!(contract.profiles.get(&alice).unwrap().storage_paid, paid);
+        let refund = get_created_receipts()
+            .iter()
+            .flat_map(|receipt| receipt.actions.clone())
+            .filter_map(|action| match action {
+                MockAction::Transfer { deposit, .. } => Some(deposit),
+                _ => None,
+            })
+            .fold(NearToken::from_yoctonear(0), NearToken::saturating_add);
+        assert_eq!(refund, NearToken::from_millinear(100).saturating_add(charged));
+    }
+
+    #[test]
+    fn test_create_group() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group(
+            "test-group".to_string(),
+            Some("Test".to_string()),
+            "{}".to_string(),
+            None,
+        );
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert_eq!(group.creator.to_string(), "alice.near");
+        assert_eq!(group.members.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Group ID must not be empty")]
+    fn test_create_group_empty_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group(String::new(), None, "{}".to_string(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Group ID must be at most 64 bytes")]
+    fn test_create_group_oversized_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group("a".repeat(65), None, "{}".to_string(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Group ID may only contain letters, digits, '_' and '-'")]
+    fn test_create_group_id_with_space() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group("my group".to_string(), None, "{}".to_string(), None);
+    }
+
+    #[test]
+    fn test_create_group_valid_id() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let group_id = format!("Team_chat-{}", "x".repeat(54));
+        assert_eq!(group_id.len(), 64);
+        contract.create_group(group_id.clone(), None, "{}".to_string(), None);
+        assert!(contract.get_group(group_id).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "member_keys must be a JSON object")]
+    fn test_create_group_malformed_member_keys() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group("g".to_string(), None, "{not json".to_string(), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid member account id in member_keys: Not An Account")]
+    fn test_create_group_bad_member_account() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let member_keys = serde_json::json!({ "Not An Account": "a2V5" }).to_string();
+        contract.create_group("g".to_string(), None, member_keys, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "member_keys value for bob.near must be non-empty base64")]
+    fn test_create_group_bad_member_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        let member_keys = serde_json::json!({ "bob.near": "" }).to_string();
+        contract.create_group("g".to_string(), None, member_keys, None);
+    }
+
+    #[test]
+    fn test_create_group_members_from_member_keys() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        let members: Vec<String> = group.members.iter().map(|m| m.to_string()).collect();
+        assert_eq!(members, vec!["alice.near", "bob.near"]);
+    }
+
+    fn create_test_group(contract: &mut WhisperContract) {
+        contract.create_group(
+            "test-group".to_string(),
+            None,
+            serde_json::json!({ "alice.near": "a2V5LWE=", "bob.near": "a2V5LWI=" }).to_string(),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_group_member_can_post() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 1);
+    }
+
+    #[test]
+    fn test_group_read_cursor() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let message_id = contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(get_context("bob.near").build());
+        let bob: AccountId = "bob.near".parse().unwrap();
+        assert_eq!(contract.get_group_read_cursor("test-group".to_string(), bob.clone()), 0);
+        contract.set_group_read_cursor("test-group".to_string(), message_id);
+        assert_eq!(
+            contract.get_group_read_cursor("test-group".to_string(), bob),
+            message_id
+        );
+        assert_eq!(
+            contract.get_group_read_cursor("test-group".to_string(), "alice.near".parse().unwrap()),
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not a group member")]
+    fn test_group_read_cursor_non_member() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("carol.near").build());
+        contract.set_group_read_cursor("test-group".to_string(), 0);
+    }
+
+    #[test]
+    fn test_group_mute() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        let bob: AccountId = "bob.near".parse().unwrap();
+        assert!(!contract.is_group_muted("test-group".to_string(), bob.clone()));
+        contract.set_group_muted("test-group".to_string(), true);
+        assert!(contract.is_group_muted("test-group".to_string(), bob.clone()));
+        assert!(!contract.is_group_muted("test-group".to_string(), "alice.near".parse().unwrap()));
+
+        contract.set_group_muted("test-group".to_string(), false);
+        assert!(!contract.is_group_muted("test-group".to_string(), bob));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not a group member")]
+    fn test_group_mute_non_member() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("carol.near").build());
+        contract.set_group_muted("test-group".to_string(), true);
+    }
+
+    #[test]
+    fn test_group_message_with_attachment() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            Some("ipfs://bafyattachment".to_string()),
+            Some(2048),
+            None,
+        );
+
+        let event = last_event();
+        assert_eq!(event["data"]["attachment_ref"], "ipfs://bafyattachment");
+        assert_eq!(event["data"]["attachment_size"], 2048);
+    }
+
+    #[test]
+    #[should_panic(expected = "attachment_size requires an attachment_ref")]
+    fn test_group_message_attachment_size_without_ref() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            Some(2048),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_group_message_gas_estimate() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let two_members = contract.group_message_gas_estimate("test-group".to_string());
+
+        contract.add_group_member(
+            "test-group".to_string(),
+            "carol.near".parse().unwrap(),
+            "key-c".to_string(),
+        );
+        let three_members = contract.group_message_gas_estimate("test-group".to_string());
+        assert!(three_members > two_members);
+        assert_eq!(three_members - two_members, GROUP_MESSAGE_GAS_PER_MEMBER.as_gas());
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient gas for group of 2 members")]
+    fn test_group_message_insufficient_gas() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        let mut context = get_context("alice.near");
+        context.prepaid_gas(Gas::from_tgas(1));
+        testing_env!(context.build());
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_group_reply() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_group_message(
+            "test-group".to_string(),
+            "reply".to_string(),
+            test_nonce(),
+            1,
+            Some("1".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        let event = last_event();
+        assert_eq!(event["event"], "group_message");
+        assert_eq!(event["data"]["id"], 2);
+        assert_eq!(event["data"]["reply_to"], "1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Not a group member")]
+    fn test_group_non_member_rejected() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("mallory.near").build());
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_add_group_member() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.add_group_member(
+            "test-group".to_string(),
+            "carol.near".parse().unwrap(),
+            "key-c".to_string(),
+        );
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert!(group.members.contains(&"carol.near".parse().unwrap()));
+        assert_eq!(group.group_key_version, 1);
+
+        testing_env!(get_context("carol.near").build());
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_join_open_group() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group("open".to_string(), None, "{}".to_string(), Some(true));
+
+        testing_env!(get_context("carol.near").build());
+        contract.join_group("open".to_string(), "key-c".to_string());
+        let group = contract.get_group("open".to_string()).unwrap();
+        assert!(group.members.contains(&"carol.near".parse().unwrap()));
+        assert_eq!(last_event()["event"], "group_member_added");
+        let excess = NearToken::from_millinear(100).saturating_sub(GROUP_MEMBER_DEPOSIT);
+        assert!(matches!(
+            get_created_receipts()[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == excess
+        ));
+
+        contract.send_group_message(
+            "open".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Group is not open; use request_join")]
+    fn test_join_closed_group() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("carol.near").build());
+        contract.join_group("test-group".to_string(), "key-c".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract is paused")]
+    fn test_join_group_while_paused() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group("open".to_string(), None, "{}".to_string(), Some(true));
+        contract.set_paused(true);
+
+        testing_env!(get_context("carol.near").build());
+        contract.join_group("open".to_string(), "key-c".to_string());
+    }
+
+    #[test]
+    fn test_request_join() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("carol.near").build());
+        contract.request_join("test-group".to_string());
+        let event = last_event();
+        assert_eq!(event["event"], "group_join_request");
+        assert_eq!(event["data"]["account_id"], "carol.near");
+        assert_eq!(event["data"]["creator"], "alice.near");
+    }
+
+    #[test]
+    fn test_accept_group_invite() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let invite_id = contract.create_group_invite("test-group".to_string(), 1_000);
+        assert_eq!(last_event()["event"], "group_invite_created");
+        let second = contract.create_group_invite("test-group".to_string(), 1_000);
+        assert_ne!(invite_id, second);
+
+        testing_env!(get_context("carol.near").build());
+        contract.accept_group_invite(invite_id.clone(), "key-c".to_string());
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert!(group.members.contains(&"carol.near".parse().unwrap()));
+        assert_eq!(group.member_count, 3);
+
+        let event = last_event();
+        assert_eq!(event["event"], "group_invite_accepted");
+        assert_eq!(event["data"]["invite_id"], invite_id);
+        assert_eq!(event["data"]["account_id"], "carol.near");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invite has expired")]
+    fn test_accept_expired_group_invite() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let invite_id = contract.create_group_invite("test-group".to_string(), 1_000);
+
+        let mut context = get_context("carol.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+        contract.accept_group_invite(invite_id, "key-c".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invite has already been used")]
+    fn test_reuse_group_invite() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let invite_id = contract.create_group_invite("test-group".to_string(), 1_000);
+
+        testing_env!(get_context("carol.near").build());
+        contract.accept_group_invite(invite_id.clone(), "key-c".to_string());
+
+        testing_env!(get_context("dave.near").build());
+        contract.accept_group_invite(invite_id, "key-d".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only a group admin can do this")]
+    fn test_create_group_invite_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.create_group_invite("test-group".to_string(), 1_000);
+    }
+
+    #[test]
+    fn test_remove_group_member_bumps_key_version() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.remove_group_member("test-group".to_string(), "bob.near".parse().unwrap());
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert!(!group.members.contains(&"bob.near".parse().unwrap()));
+        assert_eq!(group.group_key_version, 2);
+    }
+
+    #[test]
+    fn test_remove_group_member_refunds_deposit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let deposit = contract.get_group("test-group".to_string()).unwrap().deposit;
+        assert_eq!(
+            deposit,
+            DEFAULT_GROUP_DEPOSIT.saturating_add(GROUP_MEMBER_DEPOSIT.saturating_mul(2))
+        );
+
+        testing_env!(get_context("alice.near").build());
+        contract.remove_group_member("test-group".to_string(), "bob.near".parse().unwrap());
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert_eq!(group.deposit, deposit.saturating_sub(GROUP_MEMBER_DEPOSIT));
+        let receipts = get_created_receipts();
+        assert_eq!(receipts[0].receiver_id.as_str(), "alice.near");
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == GROUP_MEMBER_DEPOSIT
+        ));
+    }
+
+    fn create_full_group(contract: &mut WhisperContract) {
+        let member_keys: serde_json::Map<String, serde_json::Value> = (1..MAX_GROUP_MEMBERS)
+            .map(|i| (format!("member{}.near", i), "a2V5".into()))
+            .collect();
+        let mut context = get_context("alice.near");
+        context.attached_deposit(NearToken::from_near(1));
+        testing_env!(context.build());
+        contract.create_group(
+            "full".to_string(),
+            None,
+            serde_json::Value::Object(member_keys).to_string(),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_group_fills_to_limit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_full_group(&mut contract);
+        let group = contract.get_group("full".to_string()).unwrap();
+        assert_eq!(group.member_count, MAX_GROUP_MEMBERS);
+        assert_eq!(group.members.len() as u32, MAX_GROUP_MEMBERS);
+
+        // Removing a member frees a slot
+        contract.remove_group_member("full".to_string(), "member1.near".parse().unwrap());
+        contract.add_group_member(
+            "full".to_string(),
+            "carol.near".parse().unwrap(),
+            "key-c".to_string(),
+        );
+        let group = contract.get_group("full".to_string()).unwrap();
+        assert_eq!(group.member_count, MAX_GROUP_MEMBERS);
+    }
+
+    #[test]
+    #[should_panic(expected = "Group is full")]
+    fn test_add_member_to_full_group() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_full_group(&mut contract);
+        contract.add_group_member(
+            "full".to_string(),
+            "carol.near".parse().unwrap(),
+            "key-c".to_string(),
+        );
+    }
+
+    #[test]
+    fn test_group_admin_adds_member() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.add_group_admin("test-group".to_string(), "bob.near".parse().unwrap());
+        assert_eq!(last_event()["event"], "group_admin_added");
+
+        testing_env!(get_context("bob.near").build());
+        contract.add_group_member(
+            "test-group".to_string(),
+            "carol.near".parse().unwrap(),
+            "key-c".to_string(),
+        );
+        contract.rotate_group_key("test-group".to_string(), "{}".to_string(), 2);
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert!(group.members.contains(&"carol.near".parse().unwrap()));
+        assert_eq!(group.group_key_version, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the group creator can remove an admin")]
+    fn test_group_admin_cannot_remove_admin() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.add_group_member(
+            "test-group".to_string(),
+            "carol.near".parse().unwrap(),
+            "key-c".to_string(),
+        );
+        contract.add_group_admin("test-group".to_string(), "bob.near".parse().unwrap());
+        contract.add_group_admin("test-group".to_string(), "carol.near".parse().unwrap());
+
+        testing_env!(get_context("bob.near").build());
+        contract.remove_group_member("test-group".to_string(), "carol.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the group creator can do this")]
+    fn test_add_group_admin_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.add_group_admin("test-group".to_string(), "bob.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only a group admin can do this")]
+    fn test_add_group_member_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.add_group_member(
+            "test-group".to_string(),
+            "mallory.near".parse().unwrap(),
+            "key-m".to_string(),
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only a group admin can do this")]
+    fn test_remove_group_member_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.remove_group_member("test-group".to_string(), "alice.near".parse().unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "Group does not exist")]
+    fn test_remove_member_unknown_group() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.remove_group_member("missing".to_string(), "bob.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_owner_views() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let contract = WhisperContract::new();
+        assert_eq!(contract.get_owner().to_string(), "alice.near");
+        assert!(contract.is_owner("alice.near".parse().unwrap()));
+        assert!(!contract.is_owner("random.near".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.transfer_ownership("bob.near".parse().unwrap());
+        assert_eq!(contract.get_stats()["owner"], "bob.near");
+
+        let event = last_event();
+        assert_eq!(event["event"], "ownership_transferred");
+        assert_eq!(event["data"]["old_owner"], "alice.near");
+        assert_eq!(event["data"]["new_owner"], "bob.near");
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can do this")]
+    fn test_transfer_ownership_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        testing_env!(get_context("mallory.near").build());
+        contract.transfer_ownership("mallory.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_pause_and_unpause() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_paused(true);
+        assert_eq!(last_event()["event"], "paused_changed");
+
+        // Key management still works while paused
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+
+        contract.set_paused(false);
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            2,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(contract.message_count, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract is paused")]
+    fn test_send_while_paused() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_paused(true);
+
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract is paused")]
+    fn test_group_message_while_paused() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.set_paused(true);
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can do this")]
+    fn test_set_paused_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        testing_env!(get_context("mallory.near").build());
+        contract.set_paused(true);
+    }
+
+    #[test]
+    fn test_rotate_group_key() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.rotate_group_key("test-group".to_string(), "{}".to_string(), 2);
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert_eq!(group.group_key_version, 2);
+
+        let event = last_event();
+        assert_eq!(event["event"], "group_key_rotated");
+        assert_eq!(event["data"]["group_key_version"], 2);
+        assert_eq!(event["data"]["member_keys"], "{}");
+    }
+
+    #[test]
+    fn test_group_pin() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.set_group_pin("test-group".to_string(), "pin-1".to_string(), test_nonce(), 1);
+        assert_eq!(last_event()["event"], "group_pin_updated");
+        let pin = contract.get_group("test-group".to_string()).unwrap().pin.unwrap();
+        assert_eq!(pin.encrypted_body, "pin-1");
+        assert_eq!(pin.pinned_by.as_str(), "alice.near");
+
+        contract.set_group_pin("test-group".to_string(), "pin-2".to_string(), test_nonce(), 1);
+        let pin = contract.get_group("test-group".to_string()).unwrap().pin.unwrap();
+        assert_eq!(pin.encrypted_body, "pin-2");
+
+        contract.set_group_pin("test-group".to_string(), String::new(), String::new(), 1);
+        assert!(contract.get_group("test-group".to_string()).unwrap().pin.is_none());
+        assert!(last_event()["data"]["encrypted_body"].is_null());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only a group admin can do this")]
+    fn test_group_pin_non_admin() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.set_group_pin("test-group".to_string(), "pin".to_string(), test_nonce(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "New key version must be greater than 2")]
+    fn test_rotate_group_key_non_increasing() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.rotate_group_key("test-group".to_string(), "{}".to_string(), 2);
+        contract.rotate_group_key("test-group".to_string(), "{}".to_string(), 2);
+    }
+
+    fn post_to_test_group(contract: &mut WhisperContract) {
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Group rate limit exceeded")]
+    fn test_group_rate_limit_exceeded() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.set_group_rate_limit("test-group".to_string(), 2, 60);
+
+        testing_env!(get_context("bob.near").build());
+        post_to_test_group(&mut contract);
+        post_to_test_group(&mut contract);
+        post_to_test_group(&mut contract);
+    }
+
+    #[test]
+    fn test_group_rate_limit_window_resets() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.set_group_rate_limit("test-group".to_string(), 2, 60);
+        assert_eq!(last_event()["event"], "group_rate_limit_changed");
+
+        testing_env!(get_context("bob.near").build());
+        post_to_test_group(&mut contract);
+        post_to_test_group(&mut contract);
+
+        // Limits are per member, and bob's window reopens after a minute
+        testing_env!(get_context("alice.near").build());
+        post_to_test_group(&mut contract);
+        let mut context = get_context("bob.near");
+        context.block_timestamp(60 * 1_000_000_000);
+        testing_env!(context.build());
+        post_to_test_group(&mut contract);
+        post_to_test_group(&mut contract);
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert_eq!(group.message_count, 5);
+    }
+
+    #[test]
+    fn test_group_rate_window_charged_to_sender() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.set_group_rate_limit("test-group".to_string(), 2, 60);
+
+        testing_env!(get_context("bob.near").build());
+        let storage_before = env::storage_usage();
+        post_to_test_group(&mut contract);
+        let storage_used = env::storage_usage() - storage_before;
+        let charged = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        assert!(!charged.is_zero());
+        let excess = NearToken::from_millinear(100).saturating_sub(charged);
+        assert!(matches!(
+            get_created_receipts()[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == excess
+        ));
+
+        // Later messages only overwrite the window
+        testing_env!(get_context("bob.near").build());
+        post_to_test_group(&mut contract);
+        assert!(matches!(
+            get_created_receipts()[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == NearToken::from_millinear(100)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage deposit")]
+    fn test_group_rate_window_requires_deposit() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.set_group_rate_limit("test-group".to_string(), 2, 60);
+
+        let mut context = get_context("bob.near");
+        context.attached_deposit(NearToken::from_yoctonear(0));
+        testing_env!(context.build());
+        post_to_test_group(&mut contract);
+    }
+
+    #[test]
+    fn test_group_message_count() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        for _ in 0..2 {
+            contract.send_group_message(
+                "test-group".to_string(),
+                "encrypted".to_string(),
+                test_nonce(),
+                1,
+                None,
+                None,
+                None,
+                None,
+                None,
+            );
+        }
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert_eq!(group.message_count, 2);
+
+        let stats = contract.get_group_stats("test-group".to_string()).unwrap();
+        assert_eq!(stats["message_count"], 2);
+        assert_eq!(stats["member_count"], 2);
+        assert_eq!(stats["created_at"], group.created_at);
+        assert!(contract.get_group_stats("missing".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_rename_group() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        contract.rename_group("test-group".to_string(), Some("Renamed".to_string()));
+
+        let group = contract.get_group("test-group".to_string()).unwrap();
+        assert_eq!(group.name.as_deref(), Some("Renamed"));
+        assert_eq!(last_event()["event"], "group_renamed");
+    }
+
+    #[test]
+    #[should_panic(expected = "Group does not exist")]
+    fn test_delete_group() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+        let deposit = contract.get_group("test-group".to_string()).unwrap().deposit;
+        contract.delete_group("test-group".to_string());
+        assert!(contract.get_group("test-group".to_string()).is_none());
+        assert_eq!(last_event()["event"], "group_deleted");
+        assert!(get_created_receipts().iter().any(|receipt| matches!(
+            receipt.actions[..],
+            [MockAction::Transfer { deposit: refund, .. }] if refund == deposit
+        )));
+
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+
+    #[test]
+    fn test_get_groups_by_creator() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group("first".to_string(), None, "{}".to_string(), None);
+        contract.create_group("second".to_string(), None, "{}".to_string(), None);
+
+        let groups = contract.get_groups_by_creator("alice.near".parse().unwrap(), 0, 10);
+        let ids: Vec<&str> = groups.iter().map(|g| g.group_id.as_str()).collect();
+        assert_eq!(ids, vec!["first", "second"]);
+
+        let page = contract.get_groups_by_creator("alice.near".parse().unwrap(), 1, 10);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].group_id, "second");
+
+        contract.delete_group("first".to_string());
+        let groups = contract.get_groups_by_creator("alice.near".parse().unwrap(), 0, 10);
+        assert_eq!(groups.len(), 1);
+        assert!(contract
+            .get_groups_by_creator("bob.near".parse().unwrap(), 0, 10)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_get_group_members() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_group(
+            "test-group".to_string(),
+            None,
+            r#"{"bob.near":"a2V5LWI=","carol.near":"a2V5LWM="}"#.to_string(),
+            None,
+        );
+
+        let members = contract.get_group_members("test-group".to_string(), 0, 2);
+        assert_eq!(
+            members,
+            vec!["alice.near".parse::<AccountId>().unwrap(), "bob.near".parse().unwrap()]
+        );
+        let rest = contract.get_group_members("test-group".to_string(), 2, 2);
+        assert_eq!(rest, vec!["carol.near".parse::<AccountId>().unwrap()]);
+        assert!(contract.get_group_members("test-group".to_string(), 3, 2).is_empty());
+        assert!(contract.get_group_members("missing".to_string(), 0, 10).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the group creator can do this")]
+    fn test_delete_group_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
+
+        testing_env!(get_context("bob.near").build());
+        contract.delete_group("test-group".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the group creator can do this")]
+    fn test_rename_group_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        create_test_group(&mut contract);
 
-        self.groups.insert(&group_id, &group);
+        testing_env!(get_context("bob.near").build());
+        contract.rename_group("test-group".to_string(), None);
+    }
 
-        emit_event(
-            "group_created",
-            serde_json::json!({
-                "group_id": group_id,
-                "creator": creator.to_string(),
-                "name": name,
-                "member_keys": member_keys,
-                "timestamp": env::block_timestamp(),
-            }),
-        );
+    #[test]
+    fn test_create_channel() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_channel("news".to_string(), Some("News".to_string()));
+
+        let channel = contract.get_channel("news".to_string()).unwrap();
+        assert_eq!(channel.creator.to_string(), "alice.near");
+        assert_eq!(channel.name.as_deref(), Some("News"));
+        assert_eq!(channel.subscriber_count, 0);
+        assert_eq!(last_event()["event"], "channel_created");
     }
 
-    pub fn send_group_message(
-        &mut self,
-        group_id: String,
-        encrypted_body: String,
-        nonce: String,
-        group_key_version: u32,
-    ) {
-        let from = env::predecessor_account_id();
+    #[test]
+    fn test_subscribe_and_unsubscribe() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
 
-        assert!(
-            self.groups.get(&group_id).is_some(),
-            "Group does not exist"
-        );
+        let mut contract = WhisperContract::new();
+        contract.create_channel("news".to_string(), None);
 
-        self.message_count += 1;
-        let message_id = self.message_count;
+        testing_env!(get_context("bob.near").build());
+        let storage_before = env::storage_usage();
+        contract.subscribe("news".to_string());
+        assert!(contract.is_subscribed("news".to_string(), "bob.near".parse().unwrap()));
+        assert_eq!(contract.get_channel("news".to_string()).unwrap().subscriber_count, 1);
+        let storage_used = env::storage_usage() - storage_before;
+        let charged = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        let excess = NearToken::from_millinear(100).saturating_sub(charged);
+        assert!(matches!(
+            get_created_receipts()[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == excess
+        ));
 
-        emit_event(
-            "group_message",
-            serde_json::json!({
-                "id": message_id,
-                "group_id": group_id,
-                "from": from.to_string(),
-                "encrypted_body": encrypted_body,
-                "nonce": nonce,
-                "group_key_version": group_key_version,
-                "timestamp": env::block_timestamp(),
-            }),
-        );
+        testing_env!(get_context("bob.near").build());
+        contract.unsubscribe("news".to_string());
+        assert!(!contract.is_subscribed("news".to_string(), "bob.near".parse().unwrap()));
+        assert_eq!(contract.get_channel("news".to_string()).unwrap().subscriber_count, 0);
+        assert!(matches!(
+            get_created_receipts()[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == charged
+        ));
     }
 
-    // ========================================================================
-    // View Methods
-    // ========================================================================
+    #[test]
+    fn test_broadcast() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
 
-    pub fn get_profile(&self, account_id: AccountId) -> Option<MessagingProfile> {
-        self.profiles.get(&account_id)
-    }
+        let mut contract = WhisperContract::new();
+        contract.create_channel("news".to_string(), None);
+        let id = contract.broadcast("news".to_string(), "encrypted".to_string(), test_nonce());
 
-    pub fn has_profile(&self, account_id: AccountId) -> bool {
-        self.profiles.get(&account_id).is_some()
+        let event = last_event();
+        assert_eq!(event["event"], "broadcast");
+        assert_eq!(event["data"]["id"], id);
+        assert_eq!(event["data"]["channel_id"], "news");
+        assert_eq!(event["data"]["from"], "alice.near");
+        assert_eq!(event["data"]["encrypted_body"], "encrypted");
     }
 
-    pub fn get_group(&self, group_id: String) -> Option<GroupChat> {
-        self.groups.get(&group_id)
+    #[test]
+    #[should_panic(expected = "Only the channel creator can broadcast")]
+    fn test_broadcast_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        contract.create_channel("news".to_string(), None);
+
+        testing_env!(get_context("bob.near").build());
+        contract.subscribe("news".to_string());
+        contract.broadcast("news".to_string(), "encrypted".to_string(), test_nonce());
     }
 
-    pub fn get_stats(&self) -> serde_json::Value {
-        serde_json::json!({
-            "profile_count": self.profile_count,
-            "message_count": self.message_count,
-            "owner": self.owner.to_string(),
-        })
+    #[test]
+    fn test_event_schema_version() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        assert_eq!(contract.event_schema_version(), EVENT_VERSION);
+
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        let event = last_event();
+        assert_eq!(event["standard"], DEFAULT_EVENT_STANDARD);
+        assert_eq!(event["version"], EVENT_VERSION);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use near_sdk::test_utils::VMContextBuilder;
-    use near_sdk::testing_env;
+    #[test]
+    fn test_version() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
 
-    fn get_context(predecessor: &str) -> VMContextBuilder {
-        let mut builder = VMContextBuilder::new();
-        builder.predecessor_account_id(predecessor.parse().unwrap());
-        builder.attached_deposit(NearToken::from_millinear(100));
-        builder
+        let contract = WhisperContract::new();
+        assert_eq!(contract.version(), CONTRACT_VERSION);
+        assert_eq!(contract.version(), "0.2.0");
     }
 
     #[test]
-    fn test_register_key() {
+    fn test_set_event_standard() {
         let context = get_context("alice.near");
         testing_env!(context.build());
 
         let mut contract = WhisperContract::new();
-        let pubkey = BASE64.encode([1u8; 32]);
-        contract.register_key(pubkey.clone(), Some("Alice".to_string()));
+        contract.set_event_standard("whisper-fork".to_string());
+        assert_eq!(contract.get_stats()["event_standard"], "whisper-fork");
 
-        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
-        assert_eq!(profile.x25519_pubkey, pubkey);
-        assert_eq!(profile.key_version, 1);
-        assert_eq!(contract.profile_count, 1);
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        assert_eq!(last_event()["standard"], "whisper-fork");
     }
 
     #[test]
-    fn test_rotate_key() {
+    #[should_panic(expected = "Event standard must be 1-32 bytes")]
+    fn test_set_event_standard_too_long() {
         let context = get_context("alice.near");
         testing_env!(context.build());
 
         let mut contract = WhisperContract::new();
-        let pubkey1 = BASE64.encode([1u8; 32]);
-        contract.register_key(pubkey1, None);
+        contract.set_event_standard("x".repeat(33));
+    }
 
-        let pubkey2 = BASE64.encode([2u8; 32]);
-        contract.register_key(pubkey2.clone(), None);
+    #[test]
+    fn test_migrate_preserves_state() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
 
-        let profile = contract.get_profile("alice.near".parse().unwrap()).unwrap();
-        assert_eq!(profile.x25519_pubkey, pubkey2);
-        assert_eq!(profile.key_version, 2);
-        assert_eq!(contract.profile_count, 1);
+        let mut profiles = LookupMap::new(StorageKey::Profiles);
+        for (account, name) in [("bob.near", "Bob"), ("carol.near", "Carol")] {
+            let profile = LegacyProfile {
+                x25519_pubkey: BASE64.encode([1u8; 32]),
+                key_version: 1,
+                registered_at: 42,
+                display_name: Some(name.to_string()),
+            };
+            profiles.insert(&account.parse().unwrap(), &profile);
+        }
+        let mut groups = LookupMap::new(StorageKey::Groups);
+        let group = LegacyGroup {
+            group_id: "legacy".to_string(),
+            creator: "bob.near".parse().unwrap(),
+            created_at: 42,
+            name: None,
+        };
+        groups.insert(&group.group_id.clone(), &group);
+        env::state_write(&LegacyState {
+            profiles,
+            groups,
+            profile_count: 2,
+            message_count: 7,
+            owner: "owner.near".parse().unwrap(),
+        });
+
+        let mut contract = WhisperContract::migrate(vec!["bob.near".parse().unwrap()]);
+        assert_eq!(contract.profile_count, 2);
+        assert_eq!(contract.message_count, 7);
+        assert_eq!(contract.get_owner().to_string(), "owner.near");
+        assert!(contract.has_profile("bob.near".parse().unwrap()));
+        assert!(!contract.paused);
+        assert_eq!(contract.resolve_name("bob".to_string()), Some("bob.near".parse().unwrap()));
+        assert_eq!(contract.list_profiles(0, 10).len(), 1);
+        let bob = contract.get_profile("bob.near".parse().unwrap()).unwrap();
+        assert_eq!(bob.registered_at, 42);
+        assert_eq!(bob.storage_paid, LEGACY_REGISTRATION_DEPOSIT);
+        let group = contract.get_group("legacy".to_string()).unwrap();
+        assert_eq!(group.members, vec!["bob.near".parse::<AccountId>().unwrap()]);
+
+        testing_env!(get_context("owner.near").build());
+        contract.backfill_profiles(vec!["carol.near".parse().unwrap()]);
+        assert_eq!(
+            contract.resolve_name("carol".to_string()),
+            Some("carol.near".parse().unwrap())
+        );
+        assert_eq!(contract.list_profiles(0, 10).len(), 2);
+        // Backfilling again doesn't credit the deposit twice
+        contract.backfill_profiles(vec!["carol.near".parse().unwrap()]);
+        let carol = contract.get_profile("carol.near".parse().unwrap()).unwrap();
+        assert_eq!(carol.storage_paid, LEGACY_REGISTRATION_DEPOSIT);
     }
 
     #[test]
-    fn test_send_message() {
+    fn test_legacy_profile_deposit_credited_on_rotation() {
+        let context = get_context("bob.near");
+        testing_env!(context.build());
+
+        let mut profiles = LookupMap::new(StorageKey::Profiles);
+        let profile = LegacyProfile {
+            x25519_pubkey: BASE64.encode([1u8; 32]),
+            key_version: 1,
+            registered_at: 42,
+            display_name: None,
+        };
+        profiles.insert(&"bob.near".parse().unwrap(), &profile);
+        env::state_write(&LegacyState {
+            profiles,
+            groups: LookupMap::new(StorageKey::Groups),
+            profile_count: 1,
+            message_count: 0,
+            owner: "owner.near".parse().unwrap(),
+        });
+        let mut contract = WhisperContract::migrate(Vec::new());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
+        let bob = contract.get_profile("bob.near".parse().unwrap()).unwrap();
+        assert!(bob.storage_paid >= LEGACY_REGISTRATION_DEPOSIT);
+
+        // Indexed now, so neither a backfill nor another rotation credits it again
+        let paid = bob.storage_paid;
+        testing_env!(get_context("owner.near").build());
+        contract.backfill_profiles(vec!["bob.near".parse().unwrap()]);
+        testing_env!(get_context("bob.near").build());
+        let storage_before = env::storage_usage();
+        contract.register_key(BASE64.encode([3u8; 32]), None, None, None, None, None);
+        let storage_used = env::storage_usage() - storage_before;
+        let charged = env::storage_byte_cost().saturating_mul(u128::from(storage_used));
+        let bob = contract.get_profile("bob.near".parse().unwrap()).unwrap();
+        assert_eq!(bob.storage_paid, paid.saturating_add(charged));
+    }
+
+    #[test]
+    fn test_unregistering_legacy_profile_keeps_others_name_claim() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+
+        let mut profiles = LookupMap::new(StorageKey::Profiles);
+        let profile = MessagingProfile {
+            x25519_pubkey: BASE64.encode([1u8; 32]),
+            key_version: 1,
+            display_name: Some("Bob".to_string()),
+            ..Default::default()
+        };
+        profiles.insert(&"bob.near".parse().unwrap(), &profile);
+        env::state_write(&LegacyWhisperContract {
+            profiles,
+            groups: LookupMap::new(StorageKey::Groups),
+            profile_count: 1,
+            message_count: 0,
+            owner: "owner.near".parse().unwrap(),
+        });
+        // Not backfilled, so the name is free to claim
+        let mut contract = WhisperContract::migrate(Vec::new());
+        contract.register_key(
+            BASE64.encode([2u8; 32]),
+            Some("bob".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+
+        testing_env!(get_context("bob.near").build());
+        drop(contract.unregister());
+        assert_eq!(
+            contract.resolve_name("bob".to_string()),
+            Some("alice.near".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_stats() {
         let context = get_context("alice.near");
         testing_env!(context.build());
+        let contract = WhisperContract::new();
+        assert_eq!(contract.get_stats()["profile_count"], 0);
+    }
 
+    #[test]
+    fn test_stats_by_message_type() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
         let mut contract = WhisperContract::new();
-        contract.register_key(BASE64.encode([1u8; 32]), None);
+        create_test_group(&mut contract);
 
-        let context_bob = get_context("bob.near");
-        testing_env!(context_bob.build());
-        contract.register_key(BASE64.encode([2u8; 32]), None);
+        testing_env!(get_context("bob.near").build());
+        contract.register_key(BASE64.encode([2u8; 32]), None, None, None, None, None);
 
-        let context_alice = get_context("alice.near");
-        testing_env!(context_alice.build());
+        testing_env!(get_context("alice.near").build());
         contract.send_message(
             "bob.near".parse().unwrap(),
             "encrypted".to_string(),
-            "nonce".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        drop(contract.send_message_with_payment(
+            "bob.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+        ));
+        contract.send_group_message(
+            "test-group".to_string(),
+            "encrypted".to_string(),
+            test_nonce(),
             1,
             None,
+            None,
+            None,
+            None,
+            None,
         );
-        assert_eq!(contract.message_count, 1);
+
+        let stats = contract.get_stats();
+        assert_eq!(stats["message_count"], 3);
+        assert_eq!(stats["dm_count"], 1);
+        assert_eq!(stats["payment_message_count"], 1);
+        assert_eq!(stats["group_message_count"], 1);
     }
 
     #[test]
-    #[should_panic(expected = "Recipient has no registered messaging key")]
-    fn test_send_to_unregistered() {
+    fn test_withdraw() {
+        let mut context = get_context("alice.near");
+        context.account_balance(NearToken::from_near(10));
+        testing_env!(context.build());
+
+        let mut contract = WhisperContract::new();
+        drop(contract.withdraw(NearToken::from_near(1), "treasury.near".parse().unwrap()));
+
+        let event = last_event();
+        assert_eq!(event["event"], "withdrawal");
+        assert_eq!(event["data"]["to"], "treasury.near");
+        let receipts = get_created_receipts();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].receiver_id.to_string(), "treasury.near");
+        assert!(matches!(
+            receipts[0].actions[..],
+            [MockAction::Transfer { deposit, .. }] if deposit == NearToken::from_near(1)
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount exceeds withdrawable balance")]
+    fn test_withdraw_keeps_escrowed_funds() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        send_test_escrow(&mut contract);
+
+        // Only the escrowed 50 milliNEAR plus the storage reserve are left
+        let storage_usage = env::storage_usage();
+        let reserve = env::storage_byte_cost().saturating_mul(u128::from(storage_usage));
+        let mut context = get_context("alice.near");
+        context.storage_usage(storage_usage);
+        context.attached_deposit(NearToken::from_yoctonear(0));
+        context.account_balance(reserve.saturating_add(NearToken::from_millinear(50)));
+        testing_env!(context.build());
+        contract.withdraw(NearToken::from_yoctonear(1), "alice.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_available_balance() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+        send_test_escrow(&mut contract);
+
+        let storage_usage = env::storage_usage();
+        let reserve = env::storage_byte_cost().saturating_mul(u128::from(storage_usage));
+        let mut context = get_context("alice.near");
+        context.storage_usage(storage_usage);
+        context.attached_deposit(NearToken::from_yoctonear(0));
+        context.account_balance(reserve.saturating_add(NearToken::from_millinear(80)));
+        testing_env!(context.build());
+
+        // The 50 milliNEAR escrow isn't available
+        let available = contract.available_balance();
+        assert_eq!(available.0, NearToken::from_millinear(30).as_yoctonear());
+        drop(contract.withdraw(
+            NearToken::from_yoctonear(available.0),
+            "alice.near".parse().unwrap(),
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can do this")]
+    fn test_withdraw_unauthorized() {
+        let context = get_context("alice.near");
+        testing_env!(context.build());
+        let mut contract = WhisperContract::new();
+
+        testing_env!(get_context("bob.near").build());
+        contract.withdraw(NearToken::from_near(1), "bob.near".parse().unwrap());
+    }
+
+    #[test]
+    fn test_emit_daily_stats() {
         let context = get_context("alice.near");
         testing_env!(context.build());
 
         let mut contract = WhisperContract::new();
-        contract.register_key(BASE64.encode([1u8; 32]), None);
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
 
+        testing_env!(get_context("bob.near").build());
         contract.send_message(
-            "nobody.near".parse().unwrap(),
-            "data".to_string(),
-            "nonce".to_string(),
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
             1,
             None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
         );
+
+        let mut context = get_context("alice.near");
+        context.block_timestamp(1_000);
+        testing_env!(context.build());
+        contract.emit_daily_stats();
+
+        let event = last_event();
+        assert_eq!(event["event"], "stats_snapshot");
+        assert_eq!(event["data"]["profile_count"], 1);
+        assert_eq!(event["data"]["message_count"], 1);
+        assert_eq!(event["data"]["timestamp"], 1_000);
     }
 
     #[test]
-    fn test_create_group() {
+    fn test_default_ttl() {
         let context = get_context("alice.near");
         testing_env!(context.build());
 
         let mut contract = WhisperContract::new();
-        contract.create_group(
-            "test-group".to_string(),
-            Some("Test".to_string()),
-            "{}".to_string(),
-        );
+        contract.register_key(BASE64.encode([1u8; 32]), None, None, None, None, None);
+        contract.set_default_ttl(86_400);
+        assert_eq!(contract.get_stats()["default_message_ttl_seconds"], 86_400);
 
-        let group = contract.get_group("test-group".to_string()).unwrap();
-        assert_eq!(group.creator.to_string(), "alice.near");
+        testing_env!(get_context("bob.near").build());
+        contract.send_message(
+            "alice.near".parse().unwrap(),
+            "encrypted".to_string(),
+            test_nonce(),
+            1,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(last_event()["data"]["ttl"], 86_400);
     }
 
     #[test]
-    fn test_stats() {
+    #[should_panic(expected = "Only the owner can do this")]
+    fn test_set_default_ttl_unauthorized() {
         let context = get_context("alice.near");
         testing_env!(context.build());
-        let contract = WhisperContract::new();
-        assert_eq!(contract.get_stats()["profile_count"], 0);
+        let mut contract = WhisperContract::new();
+
+        testing_env!(get_context("bob.near").build());
+        contract.set_default_ttl(60);
     }
 }